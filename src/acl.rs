@@ -0,0 +1,54 @@
+use crate::config::{AclAction, Config};
+use ipnet::IpNet;
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Client access control, checked against the source address of every query.
+///
+/// A client matching an `allow` network is always served. Otherwise it is refused when it matches a `deny`
+/// network, or when `allow` networks are configured and it matches none of them.
+#[derive(Debug, Default)]
+pub(crate) struct Acl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    action: AclAction,
+    rejected: AtomicU64,
+}
+
+impl Acl {
+    pub(crate) fn new(config: &Config) -> Self {
+        Acl {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+            action: config.acl_action,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn is_allowed(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        if self.allow.iter().any(|net| net.contains(&addr)) {
+            return true;
+        }
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        self.allow.is_empty()
+    }
+
+    /// Check a client, counting it as rejected when it is not allowed.
+    pub(crate) fn check(&self, addr: IpAddr) -> Option<AclAction> {
+        if self.is_allowed(addr) {
+            return None;
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        log::debug!("Client {} rejected by access control", addr);
+        Some(self.action)
+    }
+
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
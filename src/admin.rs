@@ -0,0 +1,229 @@
+//! The admin API on `--admin-addr`: the status of the running instance and its effective configuration, and a few
+//! controls. Requests from web pages, which carry an `Origin` header, are refused. With `--admin-token` every request
+//! needs the token as `Authorization: Bearer <token>`, without it only requests naming a loopback address as their
+//! `Host` are answered, which keeps DNS rebinding out.
+//!
+//! - `GET /status`: state, uptime, listeners, proxy and upstream health, cache statistics, as JSON
+//! - `GET /config`: the effective configuration as TOML, with the proxy password and the admin token redacted
+//! - `GET /log-level`, `POST /log-level?level=debug`: the maximum log level
+//! - `POST /cache/flush`: drop every cached answer
+//! - `POST /upstreams/<name>/down`, `POST /upstreams/<name>/up`: stop or resume sending queries to an upstream
+//! - `POST /shutdown`: stop gracefully, like Ctrl-C
+
+use crate::{
+    ArgVerbosity, Context, Listening, SharedContext, config_file,
+    health::Status,
+    http::{self, Request, Response},
+    querylog::{json_option, json_string},
+    socks_resolve,
+};
+use socks5_impl::Result;
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// Time left to send the response to a shutdown request before the listeners go away.
+const SHUTDOWN_DELAY: Duration = Duration::from_millis(100);
+
+const JSON: &str = "application/json";
+
+/// What the admin API reports on and controls.
+#[derive(Clone)]
+pub(crate) struct Admin {
+    pub(crate) ctx: SharedContext,
+    pub(crate) listening: Listening,
+    pub(crate) started: Instant,
+    pub(crate) shutdown_token: CancellationToken,
+}
+
+/// Answer the admin requests until shutdown.
+pub(crate) async fn serve(listener: TcpListener, admin: Admin, shutdown_token: CancellationToken) -> Result<()> {
+    let handler = move |request: Request| {
+        let admin = admin.clone();
+        async move { admin.handle(request).await }
+    };
+    http::serve(listener, handler, shutdown_token).await
+}
+
+impl Admin {
+    async fn handle(&self, request: Request) -> Response {
+        let ctx = self.ctx.borrow().clone();
+        if let Some(response) = refuse(&ctx, &request) {
+            return response;
+        }
+        let method = request.method.as_str();
+        let path = request.path.trim_end_matches('/');
+        match (path, method) {
+            ("/status", "GET") => Response::new(200, JSON, self.status(&ctx).await),
+            ("/config", "GET") => Response::new(200, "application/toml", config_file::redacted_toml(&ctx.config)),
+            ("/log-level", "GET") => Response::new(200, JSON, log_level()),
+            ("/log-level", "POST" | "PUT") => set_log_level(&request),
+            ("/cache/flush", "POST") => {
+                ctx.cache.run_pending_tasks().await;
+                let entries = ctx.cache.entry_count();
+                ctx.cache.invalidate_all();
+                log::info!("Cache flushed through the admin API, {} answers dropped", entries);
+                Response::new(200, JSON, format!("{{\"flushed\":{entries}}}"))
+            }
+            ("/shutdown", "POST") => {
+                log::info!("Shutdown requested through the admin API");
+                let token = self.shutdown_token.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(SHUTDOWN_DELAY).await;
+                    token.cancel();
+                });
+                Response::new(200, JSON, "{\"shutdown\":true}".to_owned())
+            }
+            ("/status" | "/config" | "/log-level" | "/cache/flush" | "/shutdown", _) => Response::method_not_allowed(),
+            _ => match path.strip_prefix("/upstreams/").and_then(|rest| rest.rsplit_once('/')) {
+                Some((upstream, action @ ("down" | "up"))) if method == "POST" => mark_upstream(&ctx, upstream, action == "down"),
+                Some((_, "down" | "up")) => Response::method_not_allowed(),
+                _ => Response::not_found(),
+            },
+        }
+    }
+
+    async fn status(&self, ctx: &Context) -> String {
+        let mut out = String::from("{\"running\":true");
+        _ = write!(out, ",\"version\":{}", json_string(env!("CARGO_PKG_VERSION")));
+        _ = write!(out, ",\"uptime_seconds\":{}", self.started.elapsed().as_secs());
+
+        out.push_str(",\"listeners\":[");
+        if let Ok(listening) = self.listening.lock() {
+            for (i, (kind, addr)) in listening.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                _ = write!(out, "{{\"kind\":{},\"address\":{}}}", json_string(kind), json_string(addr));
+            }
+        }
+        out.push(']');
+
+        let proxy = ctx.health.proxy();
+        _ = write!(
+            out,
+            ",\"proxy\":{{\"address\":{},{}}}",
+            json_string(&ctx.config.socks5_settings.addr.to_string()),
+            status_fields(&proxy, proxy.healthy())
+        );
+
+        out.push_str(",\"upstreams\":[");
+        for (i, upstream) in upstreams(ctx).iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let status = ctx.health.upstream(upstream);
+            let down = ctx.health.is_down(upstream);
+            _ = write!(
+                out,
+                "{{\"name\":{},\"down\":{down},{}}}",
+                json_string(upstream),
+                status_fields(&status, !down && status.healthy())
+            );
+        }
+        out.push(']');
+
+        let (hits, misses) = ctx.metrics.cache_counts();
+        // The entry count is only up to date once the pending writes are applied.
+        ctx.cache.run_pending_tasks().await;
+        _ = write!(
+            out,
+            ",\"cache\":{{\"enabled\":{},\"entries\":{},\"hits\":{hits},\"misses\":{misses}}}}}",
+            ctx.config.cache_records,
+            ctx.cache.entry_count()
+        );
+        out
+    }
+}
+
+/// The response to a request the admin API does not answer, if it is one.
+fn refuse(ctx: &Context, request: &Request) -> Option<Response> {
+    if request.header("origin").is_some() {
+        return Some(Response::new(
+            403,
+            "text/plain",
+            "cross-origin requests are not allowed\n".to_owned(),
+        ));
+    }
+    match &ctx.config.admin_token {
+        Some(token) => {
+            let given = request.header("authorization").and_then(|value| value.strip_prefix("Bearer "));
+            let valid = given.is_some_and(|given| {
+                // Compared in constant time, so the time taken does not tell how much of the token matched.
+                given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+            });
+            (!valid).then(|| Response::new(401, "text/plain", "missing or wrong bearer token\n".to_owned()))
+        }
+        None => {
+            let loopback = |host: &str| {
+                let ip = host
+                    .parse::<SocketAddr>()
+                    .map(|addr| addr.ip())
+                    .or_else(|_| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>());
+                ip.is_ok_and(|ip| ip.is_loopback())
+            };
+            let host = request.header("host");
+            (!host.is_none_or(loopback)).then(|| Response::new(403, "text/plain", "host is not a loopback address\n".to_owned()))
+        }
+    }
+}
+
+fn status_fields(status: &Status, healthy: bool) -> String {
+    let time = |time: Option<SystemTime>| {
+        time.map(|time| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    };
+    format!(
+        "\"healthy\":{healthy},\"last_success\":{},\"last_failure\":{},\"last_error\":{},\"consecutive_failures\":{}",
+        json_option(time(status.last_success)),
+        json_option(time(status.last_failure)),
+        json_option(status.last_error.as_ref()),
+        status.consecutive_failures
+    )
+}
+
+/// The upstreams of the current configuration, by the names of the metrics and the query log.
+fn upstreams(ctx: &Context) -> Vec<String> {
+    if let Some(upstream) = &ctx.upstream {
+        return vec![upstream.name()];
+    }
+    let opt = &ctx.config;
+    match (opt.socks_resolve, opt.socks_resolve_fallback) {
+        (true, false) => vec![socks_resolve::UPSTREAM_NAME.to_owned()],
+        (true, true) => vec![socks_resolve::UPSTREAM_NAME.to_owned(), opt.dns_remote_server.to_string()],
+        (false, _) => vec![opt.dns_remote_server.to_string()],
+    }
+}
+
+fn mark_upstream(ctx: &Context, upstream: &str, down: bool) -> Response {
+    let upstream = percent_encoding::percent_decode_str(upstream).decode_utf8_lossy();
+    if !upstreams(ctx).iter().any(|name| *name == upstream) {
+        return Response::not_found();
+    }
+    ctx.health.set_down(&upstream, down);
+    log::warn!(
+        "Upstream {} marked {} through the admin API",
+        upstream,
+        if down { "down" } else { "up" }
+    );
+    Response::new(200, JSON, format!("{{\"name\":{},\"down\":{down}}}", json_string(&upstream)))
+}
+
+fn log_level() -> String {
+    format!("{{\"level\":{}}}", json_string(&log::max_level().to_string().to_lowercase()))
+}
+
+fn set_log_level(request: &Request) -> Response {
+    let Some(level) = request.param("level") else {
+        return Response::bad_request("missing level parameter");
+    };
+    let Ok(verbosity) = <ArgVerbosity as clap::ValueEnum>::from_str(&level, true) else {
+        return Response::bad_request("level must be off, error, warn, info, debug or trace");
+    };
+    log::set_max_level(verbosity.into());
+    log::info!("Log level set to {} through the admin API", verbosity);
+    Response::new(200, JSON, log_level())
+}
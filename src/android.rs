@@ -74,7 +74,7 @@ pub unsafe extern "C" fn Java_com_github_shadowsocks_bg_Dns2socks_start(
                 .force_tcp(force_tcp)
                 .cache_records(cache_records)
                 .listen_addr(listen_addr.parse().map_err(std::io::Error::other)?)
-                .dns_remote_server(dns_remote_server.parse()?)
+                .dns_remote_server(dns_remote_server.parse::<socks5_impl::protocol::Address>()?)
                 .socks5_settings(ArgProxy::try_from(socks5_settings.as_str()).map_err(std::io::Error::other)?);
 
             if let Err(err) = main_entry(cfg, shutdown_token).await {
@@ -1,4 +1,4 @@
-use crate::{ArgVerbosity, main_entry};
+use crate::{ArgVerbosity, Dns2Socks, Resolver};
 use std::ffi::{c_char, c_int};
 
 static TUN_QUIT: std::sync::Mutex<Option<tokio_util::sync::CancellationToken>> = std::sync::Mutex::new(None);
@@ -8,7 +8,7 @@ static TUN_QUIT: std::sync::Mutex<Option<tokio_util::sync::CancellationToken>> =
 /// Run the dns2socks component with some arguments.
 /// Parameters:
 /// - listen_addr: the listen address, e.g. "0.0.0.0:53", or null to use the default value
-/// - dns_remote_server: the dns remote server, e.g. "8.8.8.8:53", or null to use the default value
+/// - dns_remote_server: the dns remote server, e.g. "8.8.8.8:53" or "dns.google:53", or null to use the default value
 /// - socks5_settings: the socks5 server, e.g. "socks5://[username[:password]@]host:port", or null to use the default value
 /// - force_tcp: whether to force tcp, true or false, default is false
 /// - cache_records: whether to cache dns records, true or false, default is false
@@ -73,7 +73,7 @@ pub unsafe extern "C" fn dns2socks_start(
         let Ok(dns_remote_server) = unsafe { std::ffi::CStr::from_ptr(dns_remote_server) }.to_str() else {
             return -5;
         };
-        let Ok(addr) = dns_remote_server.parse() else {
+        let Ok(addr) = dns_remote_server.parse::<socks5_impl::protocol::Address>() else {
             return -6;
         };
         config.dns_remote_server(addr);
@@ -89,7 +89,14 @@ pub unsafe extern "C" fn dns2socks_start(
     }
 
     let main_loop = async move {
-        if let Err(err) = main_entry(config, shutdown_token).await {
+        let result = async {
+            let server = Dns2Socks::builder().config(config).shutdown_token(shutdown_token).start().await?;
+            server.resolver().activate(tokio::runtime::Handle::current());
+            server.wait().await
+        }
+        .await;
+        Resolver::deactivate();
+        if let Err(err) = result {
             log::error!("main loop error: {}", err);
             return Err(err);
         }
@@ -118,3 +125,86 @@ pub unsafe extern "C" fn dns2socks_stop() -> c_int {
     }
     -1
 }
+
+/// # Safety
+///
+/// Look up the domain a fake IP address was handed out for, when the running instance is in fake-IP mode.
+/// Parameters:
+/// - ip: the fake IP address, e.g. "198.18.0.1"
+/// - domain: the buffer receiving the domain as a NUL terminated string, without the trailing dot
+/// - domain_len: the size of the buffer in bytes
+///
+/// Returns the length of the domain, -1 if the address is not mapped to a domain, -2 if the address is invalid,
+/// or -3 if the buffer is too small.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dns2socks_fake_ip_lookup(ip: *const c_char, domain: *mut c_char, domain_len: usize) -> c_int {
+    if ip.is_null() || domain.is_null() {
+        return -2;
+    }
+    let Ok(ip) = unsafe { std::ffi::CStr::from_ptr(ip) }.to_str() else {
+        return -2;
+    };
+    let Ok(ip) = ip.parse() else {
+        return -2;
+    };
+    let Some(name) = crate::fake_ip_lookup(ip) else {
+        return -1;
+    };
+    if name.len() >= domain_len {
+        return -3;
+    }
+    // SAFETY: the caller guarantees `domain` points to at least `domain_len` writable bytes.
+    unsafe {
+        std::ptr::copy_nonoverlapping(name.as_ptr(), domain as *mut u8, name.len());
+        *domain.add(name.len()) = 0;
+    }
+    name.len() as c_int
+}
+
+/// # Safety
+///
+/// Resolve a name through the running instance, like a query sent to its listeners.
+/// Parameters:
+/// - name: the domain name, e.g. "example.com"
+/// - qtype: the query type, e.g. 1 for A or 28 for AAAA
+/// - buf: the buffer receiving the DNS response message, in wire format
+/// - buf_len: the size of the buffer in bytes
+///
+/// Returns the length of the response, whatever its response code, -1 if no instance is running, -2 if the name is
+/// invalid, -3 if the buffer is too small, or -4 if the query failed. It must not be called from an async runtime.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dns2socks_resolve(name: *const c_char, qtype: u16, buf: *mut u8, buf_len: usize) -> c_int {
+    use hickory_proto::{
+        op::{Message, MessageType, OpCode, Query},
+        rr::RecordType,
+    };
+
+    let Some((resolver, runtime)) = Resolver::active() else {
+        return -1;
+    };
+    if name.is_null() || buf.is_null() {
+        return -2;
+    }
+    let Ok(name) = unsafe { std::ffi::CStr::from_ptr(name) }.to_str() else {
+        return -2;
+    };
+    let Ok(name) = crate::config::parse_fqdn(name) else {
+        return -2;
+    };
+    let mut query = Message::new(rand::random::<u16>(), MessageType::Query, OpCode::Query);
+    query.add_query(Query::query(name, RecordType::from(qtype)));
+    query.metadata.recursion_desired = true;
+
+    let Ok(response) = runtime.block_on(resolver.query(query)) else {
+        return -4;
+    };
+    let Ok(data) = response.to_vec() else {
+        return -4;
+    };
+    if data.len() > buf_len {
+        return -3;
+    }
+    // SAFETY: the caller guarantees `buf` points to at least `buf_len` writable bytes.
+    unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len()) };
+    data.len() as c_int
+}
@@ -1,24 +1,45 @@
-use dns2socks_core::{Config, LIB_NAME, main_entry};
+use dns2socks_core::{Config, LIB_NAME, Reloader, main_entry_with_reloader};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let config = Config::parse_args();
-
     dotenvy::dotenv().ok();
 
-    let default = format!("{}={:?},{LIB_NAME}={:?}", module_path!(), config.verbosity, config.verbosity);
+    let config = Config::parse_args();
+
+    // Without RUST_LOG the maximum level alone filters, so the admin API can change it at runtime.
+    let default = format!("{}=trace,{LIB_NAME}=trace", module_path!());
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default)).init();
+    if std::env::var_os(env_logger::DEFAULT_FILTER_ENV).is_none() {
+        log::set_max_level(config.verbosity.into());
+    }
 
     let shutdown_token = tokio_util::sync::CancellationToken::new();
+    let reloader = Reloader::new();
     let join_handle = tokio::spawn({
         let shutdown_token = shutdown_token.clone();
+        let reloader = reloader.clone();
         async move {
-            if let Err(err) = main_entry(config, shutdown_token).await {
+            if let Err(err) = main_entry_with_reloader(config, shutdown_token, reloader).await {
                 log::error!("main loop error: {}", err);
             }
         }
     });
 
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(reload_on_sighup(hangup, reloader));
+        let mut terminate = signal(SignalKind::terminate())?;
+        let shutdown_token = shutdown_token.clone();
+        tokio::spawn(async move {
+            if terminate.recv().await.is_some() {
+                log::info!("SIGTERM received, exiting...");
+                shutdown_token.cancel();
+            }
+        });
+    }
+
     let async_ctrlc = ctrlc2::AsyncCtrlC::new(move || {
         log::info!("Ctrl-C received, exiting...");
         shutdown_token.cancel();
@@ -29,7 +50,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
         log::error!("main_entry error {}", err);
     }
 
-    tokio::time::timeout(std::time::Duration::from_millis(100), async_ctrlc).await??;
+    // The handler has not run when the instance stopped on its own, e.g. through the admin API.
+    if let Ok(result) = tokio::time::timeout(std::time::Duration::from_millis(100), async_ctrlc).await {
+        result?;
+    }
 
     Ok(())
 }
+
+/// Build the configuration again from the same command line, the config file and the environment on every SIGHUP,
+/// and apply it.
+#[cfg(unix)]
+async fn reload_on_sighup(mut hangup: tokio::signal::unix::Signal, reloader: Reloader) {
+    while hangup.recv().await.is_some() {
+        log::info!("SIGHUP received, reloading the configuration...");
+        match Config::try_parse_args() {
+            // An error applying it is logged by the instance, which keeps running as it was.
+            Ok(config) => _ = reloader.reload(config).await,
+            Err(err) => log::error!("reload error: {}", err),
+        }
+    }
+}
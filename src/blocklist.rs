@@ -0,0 +1,232 @@
+use crate::{
+    config::{BlockAction, Config, parse_fqdn},
+    dns,
+};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{A, AAAA},
+    },
+};
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+/// TTL of the answers to blocked queries.
+const BLOCKED_TTL: u32 = 60;
+
+/// Names that hosts format blocklists map to themselves, these must never end up blocked.
+const HOSTS_RESERVED: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+#[derive(Debug, Default)]
+struct Lists {
+    blocked: HashSet<Name>,
+    allowed: HashSet<Name>,
+}
+
+impl Lists {
+    /// Add the domains of a list in hosts format, AdBlock syntax or plain one domain per line.
+    /// AdBlock exception rules (`@@||domain^`) go to the allowed set whatever list they come from.
+    fn add(&mut self, content: &str, allow: bool) {
+        for line in content.lines() {
+            let line = line.trim();
+            // `!` starts an AdBlock comment, `[Adblock Plus 2.0]` style headers are skipped alongside.
+            if line.is_empty() || line.starts_with(['!', '[']) {
+                continue;
+            }
+            if let Some(rule) = line.strip_prefix("@@") {
+                if let Some(name) = adblock_domain(rule) {
+                    self.allowed.insert(name);
+                }
+                continue;
+            }
+            let set = if allow { &mut self.allowed } else { &mut self.blocked };
+            if line.starts_with("||") {
+                if let Some(name) = adblock_domain(line) {
+                    set.insert(name);
+                }
+                continue;
+            }
+
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let Some(first) = fields.next() else {
+                continue;
+            };
+            if first.parse::<std::net::IpAddr>().is_ok() {
+                let names = fields.filter(|name| !HOSTS_RESERVED.contains(&name.to_ascii_lowercase().as_str()));
+                set.extend(names.filter_map(|name| parse_fqdn(name).ok()));
+            } else if let Ok(name) = parse_fqdn(first) {
+                set.insert(name);
+            }
+        }
+    }
+}
+
+/// The domain of a basic AdBlock rule like `||example.com^`, rules with wildcards, paths or options are
+/// ignored since they can not be applied to a DNS name.
+fn adblock_domain(rule: &str) -> Option<Name> {
+    let domain = rule.strip_prefix("||")?.strip_suffix('^')?;
+    if domain.is_empty() || domain.contains(['*', '/', '$', '^', '|']) {
+        return None;
+    }
+    parse_fqdn(domain).ok()
+}
+
+/// Whether `name` or one of its parent domains is in `set`.
+fn matches_suffix(set: &HashSet<Name>, name: &Name) -> bool {
+    if set.is_empty() {
+        return false;
+    }
+    let mut name = name.clone();
+    while !name.is_root() {
+        if set.contains(&name) {
+            return true;
+        }
+        name = name.base_name();
+    }
+    false
+}
+
+/// Domains answered locally as blocked instead of being resolved through the proxy.
+#[derive(Debug, Default)]
+pub(crate) struct Blocklist {
+    blocklists: Vec<PathBuf>,
+    allowlists: Vec<PathBuf>,
+    action: BlockAction,
+    reload_interval: Duration,
+    lists: RwLock<Arc<Lists>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+    blocked: AtomicU64,
+}
+
+impl Blocklist {
+    pub(crate) fn new(config: &Config) -> Self {
+        let blocklist = Blocklist {
+            blocklists: config.blocklist.clone(),
+            allowlists: config.allowlist.clone(),
+            action: config.block_action,
+            reload_interval: Duration::from_secs(config.blocklist_reload),
+            ..Default::default()
+        };
+        blocklist.reload();
+        blocklist
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.blocklists.iter().chain(&self.allowlists)
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        self.paths().map(modified).collect()
+    }
+
+    fn reload(&self) {
+        let modified = self.modified_times();
+        let mut lists = Lists::default();
+        let lists_to_load = self.blocklists.iter().map(|path| (path, false));
+        for (path, allow) in lists_to_load.chain(self.allowlists.iter().map(|path| (path, true))) {
+            match std::fs::read_to_string(path) {
+                Ok(content) => lists.add(&content, allow),
+                Err(e) => log::warn!("Domain list {} error \"{}\"", path.display(), e),
+            }
+        }
+        if !self.blocklists.is_empty() {
+            log::info!("Loaded {} blocked and {} allowed domains", lists.blocked.len(), lists.allowed.len());
+        }
+
+        if let Ok(mut lock) = self.lists.write() {
+            *lock = Arc::new(lists);
+        }
+        if let Ok(mut lock) = self.modified.lock() {
+            *lock = modified;
+        }
+    }
+
+    /// Reload the lists every reload interval if one of them has changed, until shutdown.
+    pub(crate) async fn watch(self: Arc<Self>, shutdown_token: tokio_util::sync::CancellationToken) -> socks5_impl::Result<()> {
+        if self.blocklists.is_empty() || self.reload_interval.is_zero() {
+            shutdown_token.cancelled().await;
+            return Ok(());
+        }
+        loop {
+            tokio::select! {
+                _ = shutdown_token.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(self.reload_interval) => {}
+            }
+            let changed = self
+                .modified
+                .lock()
+                .map(|modified| *modified != self.modified_times())
+                .unwrap_or(false);
+            if changed {
+                log::info!("Domain lists changed, reloading");
+                // Lists can hold hundreds of thousands of entries, keep the parsing off the runtime threads.
+                let blocklist = self.clone();
+                tokio::task::spawn_blocking(move || blocklist.reload())
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    pub(crate) fn is_blocked(&self, name: &Name) -> bool {
+        let Ok(lists) = self.lists.read().map(|lists| lists.clone()) else {
+            return false;
+        };
+        matches_suffix(&lists.blocked, name) && !matches_suffix(&lists.allowed, name)
+    }
+
+    /// Answer a query for a blocked domain, `None` when the domain is not blocked.
+    pub(crate) fn check(&self, query: &Message) -> Option<Message> {
+        let question = query.queries.first()?;
+        if !self.is_blocked(question.name()) {
+            return None;
+        }
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+
+        let response = match self.action {
+            BlockAction::NxDomain => dns::build_response(query, ResponseCode::NXDomain),
+            BlockAction::Refuse => dns::build_response(query, ResponseCode::Refused),
+            BlockAction::Null => {
+                let mut response = dns::build_response(query, ResponseCode::NoError);
+                let rdata = match question.query_type() {
+                    RecordType::A => Some(RData::A(A(Ipv4Addr::UNSPECIFIED))),
+                    RecordType::AAAA => Some(RData::AAAA(AAAA(Ipv6Addr::UNSPECIFIED))),
+                    _ => None,
+                };
+                if let Some(rdata) = rdata {
+                    response.add_answer(Record::from_rdata(question.name().clone(), BLOCKED_TTL, rdata));
+                }
+                response
+            }
+        };
+        Some(response)
+    }
+
+    pub(crate) fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+}
@@ -1,5 +1,12 @@
-use socks5_impl::protocol::UserKey;
+use hickory_proto::rr::{
+    Name, RData, RecordType,
+    rdata::{A, AAAA, CNAME, PTR, TXT},
+};
+use ipnet::{IpNet, Ipv6Net};
+use socks5_impl::protocol::{Address, UserKey};
 use std::net::{SocketAddr, ToSocketAddrs as _};
+use std::path::PathBuf;
+use std::str::FromStr;
 
 /// Proxy server to routing DNS query to SOCKS5 server
 #[derive(clap::Parser, Debug, Clone, PartialEq, Eq)]
@@ -9,9 +16,13 @@ pub struct Config {
     #[clap(short, long, value_name = "IP:port", default_value = "0.0.0.0:53")]
     pub listen_addr: SocketAddr,
 
-    /// Remote DNS server address
-    #[clap(short, long, value_name = "IP:port", default_value = "8.8.8.8:53")]
-    pub dns_remote_server: SocketAddr,
+    /// Also accept DNS over stream connections on this Unix domain socket path
+    #[clap(long, value_name = "path")]
+    pub unix_listen: Option<PathBuf>,
+
+    /// Remote DNS server address, a host name is resolved by the SOCKS5 server rather than locally
+    #[clap(short, long, value_parser = |s: &str| Address::try_from(s), value_name = "host:port", default_value = "8.8.8.8:53")]
+    pub dns_remote_server: Address,
 
     /// SOCKS5 URL in the form socks5://[username[:password]@]host:port,
     /// Username and password are encoded in percent encoding. For example:
@@ -23,6 +34,85 @@ pub struct Config {
     #[clap(short, long)]
     pub force_tcp: bool,
 
+    /// Resolve A, AAAA and PTR queries with the SOCKS RESOLVE extension of Tor instead of querying
+    /// --dns-remote-server, other query types are answered with NOTIMP
+    #[arg(long)]
+    pub socks_resolve: bool,
+
+    /// With --socks-resolve, send the other query types to --dns-remote-server over TCP instead of answering NOTIMP
+    #[arg(long, requires = "socks_resolve")]
+    pub socks_resolve_fallback: bool,
+
+    /// Drop responses with an answer in this network, like the fake answers some resolvers send instead of
+    /// NXDOMAIN, can be repeated. Over TCP such a response is answered with NXDOMAIN
+    #[arg(long, value_parser = parse_ip_net, value_name = "CIDR")]
+    pub bogus_nxdomain: Vec<IpNet>,
+
+    /// Retry over TCP as soon as a forged or bogus UDP response is dropped, instead of waiting for the genuine one
+    #[arg(long)]
+    pub bogus_retry_tcp: bool,
+
+    /// DNS rebinding protection: remove private, loopback and link-local addresses from upstream answers
+    #[arg(long)]
+    pub rebind_protection: bool,
+
+    /// Names in this domain, and its subdomains, may resolve to private addresses despite --rebind-protection,
+    /// can be repeated
+    #[arg(long, value_parser = parse_fqdn, value_name = "domain")]
+    pub rebind_allow: Vec<Name>,
+
+    /// How to answer when --rebind-protection removed addresses from an answer
+    #[arg(long, value_name = "action", value_enum, default_value = "strip")]
+    pub rebind_action: RebindAction,
+
+    /// Answer every AAAA query with no data, for when the SOCKS5 server can not reach IPv6 destinations
+    #[arg(long)]
+    pub filter_aaaa: bool,
+
+    /// Answer AAAA queries for names in this domain, and its subdomains, with no data, can be repeated
+    #[arg(long, value_parser = parse_fqdn, value_name = "domain")]
+    pub filter_aaaa_domain: Vec<Name>,
+
+    /// Remove the ipv6hint parameter from HTTPS and SVCB records
+    #[arg(long)]
+    pub strip_ipv6_hint: bool,
+
+    /// Answer ANY queries with the minimal answer of RFC 8482 instead of resolving them
+    #[arg(long)]
+    pub refuse_any: bool,
+
+    /// DNS64: synthesize AAAA records from the A records of names that have no AAAA records, for IPv6-only
+    /// clients behind NAT64
+    #[arg(long)]
+    pub dns64: bool,
+
+    /// The /96 prefix the IPv4 addresses are embedded in by --dns64
+    #[arg(long, value_parser = parse_dns64_prefix, value_name = "CIDR", default_value = "64:ff9b::/96")]
+    pub dns64_prefix: Ipv6Net,
+
+    /// Treat AAAA records in this IPv6 network as missing, and never synthesize from A records in this IPv4
+    /// network, with --dns64. Can be repeated
+    #[arg(long, value_parser = parse_ip_net, value_name = "CIDR")]
+    pub dns64_exclude: Vec<IpNet>,
+
+    /// How the EDNS Client Subnet option (RFC 7871) of forwarded queries is handled
+    #[arg(long, value_name = "mode", value_enum, default_value = "pass")]
+    pub ecs: EcsMode,
+
+    /// The subnet sent in place of the client's with --ecs replace, e.g. 203.0.113.0/24
+    #[arg(long, value_parser = parse_ip_net, value_name = "CIDR", required_if_eq("ecs", "replace"))]
+    pub ecs_subnet: Option<IpNet>,
+
+    /// Validate the answers of the upstream with DNSSEC, the chain of trust is fetched through the proxy too.
+    /// Answers that fail validation are answered with SERVFAIL, validated answers get the AD bit, queries with the CD bit
+    /// are passed through unvalidated
+    #[arg(long, conflicts_with = "socks_resolve")]
+    pub dnssec: bool,
+
+    /// A file of DNSKEY records to trust instead of the built-in root trust anchor, with --dnssec
+    #[arg(long, value_name = "path", requires = "dnssec")]
+    pub dnssec_trust_anchor: Option<PathBuf>,
+
     /// Cache DNS query records
     #[clap(short, long)]
     pub cache_records: bool,
@@ -34,25 +124,236 @@ pub struct Config {
     /// Timeout for DNS query
     #[clap(short, long, value_name = "seconds", default_value = "5")]
     pub timeout: u64,
+
+    /// Only serve clients from this network, can be repeated.
+    /// Clients matching an allowed network are served even if they also match a denied one
+    #[arg(long, value_parser = parse_ip_net, value_name = "CIDR")]
+    pub allow: Vec<IpNet>,
+
+    /// Refuse clients from this network, can be repeated
+    #[arg(long, value_parser = parse_ip_net, value_name = "CIDR")]
+    pub deny: Vec<IpNet>,
+
+    /// How to answer clients refused by --allow/--deny
+    #[arg(long, value_name = "action", value_enum, default_value = "refuse")]
+    pub acl_action: AclAction,
+
+    /// Maximum queries per second accepted from a single client, unlimited if not set
+    #[arg(long, value_name = "qps")]
+    pub rate_limit: Option<u32>,
+
+    /// Number of queries a client may send in a burst before --rate-limit applies, defaults to the rate
+    #[arg(long, value_name = "queries")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// IPv4 prefix length clients are grouped by for rate limiting
+    #[arg(long, value_name = "bits", default_value = "32", value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub rate_limit_ipv4_prefix: u8,
+
+    /// IPv6 prefix length clients are grouped by for rate limiting
+    #[arg(long, value_name = "bits", default_value = "64", value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub rate_limit_ipv6_prefix: u8,
+
+    /// Response rate limiting for UDP: maximum identical responses per second to a single client, disabled if not set
+    #[arg(long, value_name = "rps")]
+    pub rrl: Option<u32>,
+
+    /// Send every Nth response dropped by --rrl as a truncated response instead, 0 to never send one
+    #[arg(long, value_name = "N", default_value = "2")]
+    pub rrl_slip: u32,
+
+    /// Maximum number of queries forwarded through the proxy at the same time, 0 for unlimited
+    #[arg(long, value_name = "N", default_value = "1024")]
+    pub max_concurrent_queries: usize,
+
+    /// Maximum number of TCP and Unix socket clients served at the same time, 0 for unlimited
+    #[arg(long, value_name = "N", default_value = "256")]
+    pub max_tcp_clients: usize,
+
+    /// Maximum number of UDP queries handled at the same time, 0 for unlimited
+    #[arg(long, value_name = "N", default_value = "4096")]
+    pub max_udp_queries: usize,
+
+    /// How long a query waits for one of the above to free up before it is answered with SERVFAIL
+    #[arg(long, value_name = "milliseconds", default_value = "2000")]
+    pub queue_timeout: u64,
+
+    /// Answer names found in this hosts file (/etc/hosts format) locally, can be repeated.
+    /// The file is reloaded automatically when it changes
+    #[arg(long, value_name = "path")]
+    pub hosts_file: Vec<PathBuf>,
+
+    /// Answer this record locally, in the form "name type value", e.g. "db.internal A 10.0.0.5".
+    /// Supported types are A, AAAA, CNAME, TXT and PTR, can be repeated
+    #[arg(long = "record", value_name = "record")]
+    pub static_records: Vec<StaticRecord>,
+
+    /// Block the domains listed in this file, and their subdomains, can be repeated.
+    /// Hosts format, AdBlock `||domain^` rules and plain lists of domains are understood
+    #[arg(long, value_name = "path")]
+    pub blocklist: Vec<PathBuf>,
+
+    /// Never block the domains listed in this file, and their subdomains, can be repeated.
+    /// Same formats as --blocklist, AdBlock `@@||domain^` exceptions in a blocklist are honoured too
+    #[arg(long, value_name = "path")]
+    pub allowlist: Vec<PathBuf>,
+
+    /// How to answer queries for blocked domains
+    #[arg(long, value_name = "action", value_enum, default_value = "nxdomain")]
+    pub block_action: BlockAction,
+
+    /// How often the block and allow lists are checked for changes, 0 to never reload them
+    #[arg(long, value_name = "seconds", default_value = "300")]
+    pub blocklist_reload: u64,
+
+    /// Fake-IP mode: answer A/AAAA queries with addresses from this pool and remember which domain each one
+    /// stands for, e.g. 198.18.0.0/15. Give one IPv4 and optionally one IPv6 pool
+    #[arg(long, value_parser = parse_ip_net, value_name = "CIDR")]
+    pub fake_ip: Vec<IpNet>,
+
+    /// Save the fake-IP mapping to this file, so it survives restarts
+    #[arg(long, value_name = "path", requires = "fake_ip")]
+    pub fake_ip_file: Option<PathBuf>,
+
+    /// Serve Prometheus metrics over HTTP at /metrics on this address
+    #[arg(long, value_name = "IP:port")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Write one JSON object per answered query to this file, whatever the verbosity
+    #[arg(long, value_name = "path")]
+    pub query_log: Option<PathBuf>,
+
+    /// Rotate the query log once it reaches this size, 0 for no limit
+    #[arg(long, value_name = "MiB", default_value = "100")]
+    pub query_log_max_size: u64,
+
+    /// Rotate the query log once it is this old, 0 for no limit
+    #[arg(long, value_name = "hours", default_value = "24")]
+    pub query_log_max_age: u64,
+
+    /// Number of rotated query logs kept, as <path>.1 to <path>.N
+    #[arg(long, value_name = "N", default_value = "7")]
+    pub query_log_keep: usize,
+
+    /// Log one query out of N, picked at random
+    #[arg(long, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub query_log_sample: u32,
+
+    /// Log the client addresses truncated to their /24 (IPv4) or /48 (IPv6) network
+    #[arg(long)]
+    pub query_log_anonymize: bool,
+
+    /// Send dnstap messages of the client and forwarded queries and responses to this Unix socket, as a Frame Streams
+    /// writer
+    #[arg(long, value_name = "path", conflicts_with = "dnstap_file")]
+    pub dnstap_socket: Option<PathBuf>,
+
+    /// Write dnstap messages of the client and forwarded queries and responses to this file, in the Frame Streams format
+    #[arg(long, value_name = "path")]
+    pub dnstap_file: Option<PathBuf>,
+
+    /// Serve the admin API over HTTP on this address. Without --admin-token, keep it on localhost
+    #[arg(long, value_name = "IP:port")]
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Require this bearer token on the admin API requests. Without it, the admin API only answers requests naming
+    /// a loopback address as their host
+    #[arg(long, value_name = "token")]
+    pub admin_token: Option<String>,
+
+    /// Read options from this TOML file. Environment variables named DNS2SOCKS_<OPTION> override it, and command
+    /// line flags override both
+    #[arg(long, value_name = "path")]
+    pub config: Option<PathBuf>,
+
+    /// Check the options and the files they name, print the effective configuration as TOML and exit
+    #[arg(long)]
+    pub check_config: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             listen_addr: "0.0.0.0:53".parse().unwrap(),
-            dns_remote_server: "8.8.8.8:53".parse().unwrap(),
+            unix_listen: None,
+            dns_remote_server: Address::from(("8.8.8.8".parse::<std::net::IpAddr>().unwrap(), 53)),
             socks5_settings: ArgProxy::default(),
             force_tcp: false,
+            socks_resolve: false,
+            socks_resolve_fallback: false,
+            bogus_nxdomain: Vec::new(),
+            bogus_retry_tcp: false,
+            rebind_protection: false,
+            rebind_allow: Vec::new(),
+            rebind_action: RebindAction::default(),
+            filter_aaaa: false,
+            filter_aaaa_domain: Vec::new(),
+            strip_ipv6_hint: false,
+            refuse_any: false,
+            dns64: false,
+            dns64_prefix: "64:ff9b::/96".parse().unwrap(),
+            dns64_exclude: Vec::new(),
+            ecs: EcsMode::default(),
+            ecs_subnet: None,
+            dnssec: false,
+            dnssec_trust_anchor: None,
             cache_records: false,
             verbosity: ArgVerbosity::default(),
             timeout: 5,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            acl_action: AclAction::default(),
+            rate_limit: None,
+            rate_limit_burst: None,
+            rate_limit_ipv4_prefix: 32,
+            rate_limit_ipv6_prefix: 64,
+            rrl: None,
+            rrl_slip: 2,
+            max_concurrent_queries: 1024,
+            max_tcp_clients: 256,
+            max_udp_queries: 4096,
+            queue_timeout: 2000,
+            hosts_file: Vec::new(),
+            static_records: Vec::new(),
+            blocklist: Vec::new(),
+            allowlist: Vec::new(),
+            block_action: BlockAction::default(),
+            blocklist_reload: 300,
+            fake_ip: Vec::new(),
+            fake_ip_file: None,
+            metrics_addr: None,
+            query_log: None,
+            query_log_max_size: 100,
+            query_log_max_age: 24,
+            query_log_keep: 7,
+            query_log_sample: 1,
+            query_log_anonymize: false,
+            dnstap_socket: None,
+            dnstap_file: None,
+            admin_addr: None,
+            admin_token: None,
+            config: None,
+            check_config: false,
         }
     }
 }
 
 impl Config {
+    /// Build the configuration from the defaults, the `--config` file, the environment and the command line, in
+    /// that order of precedence.
     pub fn parse_args() -> Self {
-        clap::Parser::parse()
+        crate::config_file::parse_args()
+    }
+
+    /// Build the configuration again like [`Config::parse_args`] does, to reload it. Errors are returned instead of
+    /// exiting the process.
+    pub fn try_parse_args() -> Result<Self, String> {
+        crate::config_file::try_parse_args()
+    }
+
+    /// Build the configuration from the defaults, a TOML file and the environment.
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        crate::config_file::load(path.as_ref())
     }
 
     pub fn listen_addr(&mut self, listen_addr: SocketAddr) -> &mut Self {
@@ -60,8 +361,13 @@ impl Config {
         self
     }
 
-    pub fn dns_remote_server(&mut self, dns_remote_server: SocketAddr) -> &mut Self {
-        self.dns_remote_server = dns_remote_server;
+    pub fn unix_listen(&mut self, unix_listen: Option<PathBuf>) -> &mut Self {
+        self.unix_listen = unix_listen;
+        self
+    }
+
+    pub fn dns_remote_server<A: Into<Address>>(&mut self, dns_remote_server: A) -> &mut Self {
+        self.dns_remote_server = dns_remote_server.into();
         self
     }
 
@@ -75,6 +381,96 @@ impl Config {
         self
     }
 
+    pub fn socks_resolve(&mut self, socks_resolve: bool) -> &mut Self {
+        self.socks_resolve = socks_resolve;
+        self
+    }
+
+    pub fn socks_resolve_fallback(&mut self, socks_resolve_fallback: bool) -> &mut Self {
+        self.socks_resolve_fallback = socks_resolve_fallback;
+        self
+    }
+
+    pub fn bogus_nxdomain(&mut self, bogus_nxdomain: Vec<IpNet>) -> &mut Self {
+        self.bogus_nxdomain = bogus_nxdomain;
+        self
+    }
+
+    pub fn bogus_retry_tcp(&mut self, bogus_retry_tcp: bool) -> &mut Self {
+        self.bogus_retry_tcp = bogus_retry_tcp;
+        self
+    }
+
+    pub fn rebind_protection(&mut self, rebind_protection: bool) -> &mut Self {
+        self.rebind_protection = rebind_protection;
+        self
+    }
+
+    pub fn rebind_allow(&mut self, rebind_allow: Vec<Name>) -> &mut Self {
+        self.rebind_allow = rebind_allow;
+        self
+    }
+
+    pub fn rebind_action(&mut self, rebind_action: RebindAction) -> &mut Self {
+        self.rebind_action = rebind_action;
+        self
+    }
+
+    pub fn filter_aaaa(&mut self, filter_aaaa: bool) -> &mut Self {
+        self.filter_aaaa = filter_aaaa;
+        self
+    }
+
+    pub fn filter_aaaa_domain(&mut self, filter_aaaa_domain: Vec<Name>) -> &mut Self {
+        self.filter_aaaa_domain = filter_aaaa_domain;
+        self
+    }
+
+    pub fn strip_ipv6_hint(&mut self, strip_ipv6_hint: bool) -> &mut Self {
+        self.strip_ipv6_hint = strip_ipv6_hint;
+        self
+    }
+
+    pub fn refuse_any(&mut self, refuse_any: bool) -> &mut Self {
+        self.refuse_any = refuse_any;
+        self
+    }
+
+    pub fn dns64(&mut self, dns64: bool) -> &mut Self {
+        self.dns64 = dns64;
+        self
+    }
+
+    pub fn dns64_prefix(&mut self, dns64_prefix: Ipv6Net) -> &mut Self {
+        self.dns64_prefix = dns64_prefix;
+        self
+    }
+
+    pub fn dns64_exclude(&mut self, dns64_exclude: Vec<IpNet>) -> &mut Self {
+        self.dns64_exclude = dns64_exclude;
+        self
+    }
+
+    pub fn ecs(&mut self, ecs: EcsMode) -> &mut Self {
+        self.ecs = ecs;
+        self
+    }
+
+    pub fn ecs_subnet(&mut self, ecs_subnet: Option<IpNet>) -> &mut Self {
+        self.ecs_subnet = ecs_subnet;
+        self
+    }
+
+    pub fn dnssec(&mut self, dnssec: bool) -> &mut Self {
+        self.dnssec = dnssec;
+        self
+    }
+
+    pub fn dnssec_trust_anchor(&mut self, dnssec_trust_anchor: Option<PathBuf>) -> &mut Self {
+        self.dnssec_trust_anchor = dnssec_trust_anchor;
+        self
+    }
+
     pub fn cache_records(&mut self, cache_records: bool) -> &mut Self {
         self.cache_records = cache_records;
         self
@@ -89,6 +485,283 @@ impl Config {
         self.timeout = timeout;
         self
     }
+
+    pub fn allow(&mut self, allow: Vec<IpNet>) -> &mut Self {
+        self.allow = allow;
+        self
+    }
+
+    pub fn deny(&mut self, deny: Vec<IpNet>) -> &mut Self {
+        self.deny = deny;
+        self
+    }
+
+    pub fn acl_action(&mut self, acl_action: AclAction) -> &mut Self {
+        self.acl_action = acl_action;
+        self
+    }
+
+    pub fn rate_limit(&mut self, rate_limit: Option<u32>) -> &mut Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn rate_limit_burst(&mut self, rate_limit_burst: Option<u32>) -> &mut Self {
+        self.rate_limit_burst = rate_limit_burst;
+        self
+    }
+
+    pub fn rate_limit_ipv4_prefix(&mut self, rate_limit_ipv4_prefix: u8) -> &mut Self {
+        self.rate_limit_ipv4_prefix = rate_limit_ipv4_prefix;
+        self
+    }
+
+    pub fn rate_limit_ipv6_prefix(&mut self, rate_limit_ipv6_prefix: u8) -> &mut Self {
+        self.rate_limit_ipv6_prefix = rate_limit_ipv6_prefix;
+        self
+    }
+
+    pub fn rrl(&mut self, rrl: Option<u32>) -> &mut Self {
+        self.rrl = rrl;
+        self
+    }
+
+    pub fn rrl_slip(&mut self, rrl_slip: u32) -> &mut Self {
+        self.rrl_slip = rrl_slip;
+        self
+    }
+
+    pub fn max_concurrent_queries(&mut self, max_concurrent_queries: usize) -> &mut Self {
+        self.max_concurrent_queries = max_concurrent_queries;
+        self
+    }
+
+    pub fn max_tcp_clients(&mut self, max_tcp_clients: usize) -> &mut Self {
+        self.max_tcp_clients = max_tcp_clients;
+        self
+    }
+
+    pub fn max_udp_queries(&mut self, max_udp_queries: usize) -> &mut Self {
+        self.max_udp_queries = max_udp_queries;
+        self
+    }
+
+    pub fn queue_timeout(&mut self, queue_timeout: u64) -> &mut Self {
+        self.queue_timeout = queue_timeout;
+        self
+    }
+
+    pub fn hosts_file(&mut self, hosts_file: Vec<PathBuf>) -> &mut Self {
+        self.hosts_file = hosts_file;
+        self
+    }
+
+    pub fn static_records(&mut self, static_records: Vec<StaticRecord>) -> &mut Self {
+        self.static_records = static_records;
+        self
+    }
+
+    pub fn blocklist(&mut self, blocklist: Vec<PathBuf>) -> &mut Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    pub fn allowlist(&mut self, allowlist: Vec<PathBuf>) -> &mut Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    pub fn block_action(&mut self, block_action: BlockAction) -> &mut Self {
+        self.block_action = block_action;
+        self
+    }
+
+    pub fn blocklist_reload(&mut self, blocklist_reload: u64) -> &mut Self {
+        self.blocklist_reload = blocklist_reload;
+        self
+    }
+
+    pub fn fake_ip(&mut self, fake_ip: Vec<IpNet>) -> &mut Self {
+        self.fake_ip = fake_ip;
+        self
+    }
+
+    pub fn fake_ip_file(&mut self, fake_ip_file: Option<PathBuf>) -> &mut Self {
+        self.fake_ip_file = fake_ip_file;
+        self
+    }
+
+    pub fn metrics_addr(&mut self, metrics_addr: Option<SocketAddr>) -> &mut Self {
+        self.metrics_addr = metrics_addr;
+        self
+    }
+
+    pub fn query_log(&mut self, query_log: Option<PathBuf>) -> &mut Self {
+        self.query_log = query_log;
+        self
+    }
+
+    pub fn query_log_max_size(&mut self, query_log_max_size: u64) -> &mut Self {
+        self.query_log_max_size = query_log_max_size;
+        self
+    }
+
+    pub fn query_log_max_age(&mut self, query_log_max_age: u64) -> &mut Self {
+        self.query_log_max_age = query_log_max_age;
+        self
+    }
+
+    pub fn query_log_keep(&mut self, query_log_keep: usize) -> &mut Self {
+        self.query_log_keep = query_log_keep;
+        self
+    }
+
+    pub fn query_log_sample(&mut self, query_log_sample: u32) -> &mut Self {
+        self.query_log_sample = query_log_sample;
+        self
+    }
+
+    pub fn query_log_anonymize(&mut self, query_log_anonymize: bool) -> &mut Self {
+        self.query_log_anonymize = query_log_anonymize;
+        self
+    }
+
+    pub fn dnstap_socket(&mut self, dnstap_socket: Option<PathBuf>) -> &mut Self {
+        self.dnstap_socket = dnstap_socket;
+        self
+    }
+
+    pub fn dnstap_file(&mut self, dnstap_file: Option<PathBuf>) -> &mut Self {
+        self.dnstap_file = dnstap_file;
+        self
+    }
+
+    pub fn admin_addr(&mut self, admin_addr: Option<SocketAddr>) -> &mut Self {
+        self.admin_addr = admin_addr;
+        self
+    }
+
+    pub fn admin_token(&mut self, admin_token: Option<String>) -> &mut Self {
+        self.admin_token = admin_token;
+        self
+    }
+}
+
+/// Parse a network in CIDR notation, a bare IP address is taken as a single host.
+fn parse_ip_net(s: &str) -> Result<IpNet, String> {
+    match s.parse::<IpNet>() {
+        Ok(net) => Ok(net.trunc()),
+        Err(_) => s
+            .parse::<std::net::IpAddr>()
+            .map(IpNet::from)
+            .map_err(|_| format!("`{s}` is not a valid IP network")),
+    }
+}
+
+/// Parse the DNS64 prefix, only /96 prefixes are supported.
+fn parse_dns64_prefix(s: &str) -> Result<Ipv6Net, String> {
+    let net = s
+        .parse::<Ipv6Net>()
+        .map_err(|e| format!("`{s}` is not a valid IPv6 network, {e}"))?;
+    if net.prefix_len() != 96 {
+        return Err(format!("`{s}` is not a /96 prefix"));
+    }
+    Ok(net.trunc())
+}
+
+/// A record answered locally without asking the proxy, written as `name type value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticRecord {
+    pub name: Name,
+    pub rdata: RData,
+}
+
+impl FromStr for StaticRecord {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use std::io::{Error, ErrorKind::InvalidData};
+        let invalid = |reason: &str| Error::new(InvalidData, format!("`{s}` is not a valid record, {reason}"));
+
+        let mut parts = s.trim().splitn(3, char::is_whitespace);
+        let (Some(name), Some(record_type), Some(value)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid("expected \"name type value\""));
+        };
+        let name = parse_fqdn(name).map_err(|e| invalid(&e.to_string()))?;
+        let record_type = RecordType::from_str(&record_type.to_ascii_uppercase()).map_err(|e| invalid(&e.to_string()))?;
+        let value = value.trim();
+        let rdata = match record_type {
+            RecordType::A => RData::A(A(value.parse().map_err(|_| invalid("bad IPv4 address"))?)),
+            RecordType::AAAA => RData::AAAA(AAAA(value.parse().map_err(|_| invalid("bad IPv6 address"))?)),
+            RecordType::CNAME => RData::CNAME(CNAME(parse_fqdn(value).map_err(|e| invalid(&e.to_string()))?)),
+            RecordType::PTR => RData::PTR(PTR(parse_fqdn(value).map_err(|e| invalid(&e.to_string()))?)),
+            RecordType::TXT => {
+                let text = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                RData::TXT(TXT::new(vec![text.to_owned()]))
+            }
+            _ => return Err(invalid("only A, AAAA, CNAME, TXT and PTR are supported")),
+        };
+        Ok(StaticRecord { name, rdata })
+    }
+}
+
+impl std::fmt::Display for StaticRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.name, self.rdata.record_type(), self.rdata)
+    }
+}
+
+/// Parse a domain name, treating it as fully qualified whether or not it ends with a dot.
+pub(crate) fn parse_fqdn(name: &str) -> Result<Name, hickory_proto::ProtoError> {
+    let mut name = Name::from_str(name)?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+/// What to do with a query from a client that is not allowed by the access control lists.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Default)]
+pub enum AclAction {
+    /// Answer with REFUSED
+    #[default]
+    Refuse,
+    /// Silently drop the query
+    Drop,
+}
+
+/// How to answer a query for a domain on a blocklist.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Default)]
+pub enum BlockAction {
+    /// Answer with NXDOMAIN
+    #[default]
+    #[value(name = "nxdomain")]
+    NxDomain,
+    /// Answer A queries with 0.0.0.0, AAAA queries with :: and other types with no data
+    Null,
+    /// Answer with REFUSED
+    Refuse,
+}
+
+/// How to answer a query whose answer had private addresses removed by the DNS rebinding protection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Default)]
+pub enum RebindAction {
+    /// Answer with the remaining records, possibly none
+    #[default]
+    Strip,
+    /// Answer with NXDOMAIN
+    #[value(name = "nxdomain")]
+    NxDomain,
+}
+
+/// How the EDNS Client Subnet option of a query is handled before it is forwarded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Default)]
+pub enum EcsMode {
+    /// Remove the option, the upstream only sees the address of the SOCKS5 server
+    Strip,
+    /// Forward the option the client sent unchanged
+    #[default]
+    Pass,
+    /// Send the subnet of --ecs-subnet instead of the client's
+    Replace,
 }
 
 #[repr(C)]
@@ -0,0 +1,505 @@
+//! The `--config` TOML file and the DNS2SOCKS_<OPTION> environment variables, layered between the defaults and the
+//! command line. Both are turned into command line arguments, so every option is parsed and checked by clap in the
+//! same way whatever it comes from.
+//!
+//! The keys of the file are the option names, in snake case. Options can also be grouped in sections, a key in a
+//! section stands for the option named `<section>_<key>`, or for the one given in [`SECTION_ALIASES`]:
+//!
+//! ```toml
+//! verbosity = "info"
+//!
+//! [listen]
+//! addr = "127.0.0.1:5353"
+//!
+//! [proxy]
+//! url = "socks5://127.0.0.1:1080"
+//!
+//! [upstream]
+//! server = "1.1.1.1:53"
+//!
+//! [cache]
+//! records = true
+//! ```
+//!
+//! The file has the options of the command line, no more: an instance forwards through one proxy to one upstream,
+//! so `[proxy]` and `[upstream]` are single sections, not lists. The options that can be repeated on the command
+//! line, such as `blocklist` or `allow`, are arrays.
+
+use crate::config::Config;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, error::ErrorKind, parser::ValueSource};
+use std::{ffi::OsString, path::Path};
+use toml::{Table, Value};
+
+/// Prefix of the environment variables, followed by the option name in upper case.
+const ENV_PREFIX: &str = "DNS2SOCKS_";
+
+/// Keys of the sections that do not follow the `<section>_<key>` naming, as `(section, key, option)`.
+const SECTION_ALIASES: &[(&str, &str, &str)] = &[
+    ("listen", "addr", "listen_addr"),
+    ("listen", "unix", "unix_listen"),
+    ("proxy", "url", "socks5_settings"),
+    ("upstream", "server", "dns_remote_server"),
+];
+
+/// What the secrets of a configuration are replaced with when it is shown.
+const REDACTED: &str = "REDACTED";
+
+/// Options about building the configuration itself, that can only be given on the command line.
+const COMMAND_LINE_ONLY: &[&str] = &["config", "check_config", "help", "version"];
+
+/// The arguments an option of the file or the environment stands for.
+struct Layered {
+    id: String,
+    args: Vec<OsString>,
+}
+
+pub(crate) fn parse_args() -> Config {
+    let (command, matches) = matches().unwrap_or_else(|e| e.exit());
+    let config = Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if config.check_config {
+        if let Err(e) = check(&config) {
+            command.clone().error(ErrorKind::InvalidValue, e).exit();
+        }
+        print!("{}", effective_config(&command, &matches));
+        std::process::exit(0);
+    }
+    config
+}
+
+/// Check what parsing the options does not: the files they name, and the parts of the instance built from them
+/// before the listeners are bound.
+fn check(config: &Config) -> Result<(), String> {
+    let inputs = config.hosts_file.iter().map(|path| ("hosts file", path));
+    let inputs = inputs.chain(config.blocklist.iter().map(|path| ("blocklist", path)));
+    let inputs = inputs.chain(config.allowlist.iter().map(|path| ("allowlist", path)));
+    // The fake IP file is written on exit, it only has to exist to be restored from.
+    let inputs = inputs.chain(
+        config
+            .fake_ip_file
+            .iter()
+            .filter(|path| path.exists())
+            .map(|path| ("fake IP file", path)),
+    );
+    for (what, path) in inputs {
+        std::fs::File::open(path).map_err(|e| format!("{what} {} error \"{e}\"", path.display()))?;
+    }
+    let outputs = [
+        ("fake IP file", &config.fake_ip_file),
+        ("query log", &config.query_log),
+        ("dnstap socket", &config.dnstap_socket),
+        ("dnstap file", &config.dnstap_file),
+    ];
+    for (what, path) in outputs.into_iter().filter_map(|(what, path)| Some((what, path.as_ref()?))) {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        if !dir.is_dir() {
+            return Err(format!("{what} {} error \"no directory {}\"", path.display(), dir.display()));
+        }
+    }
+    // Creating the dnstap file would truncate the one of a running instance, its directory is checked above.
+    let mut config = config.clone();
+    config.dnstap_file = None;
+    crate::Context::new(config).map(drop).map_err(|e| e.to_string())
+}
+
+pub(crate) fn try_parse_args() -> Result<Config, String> {
+    let (_, matches) = matches().map_err(error_message)?;
+    Config::from_arg_matches(&matches).map_err(error_message)
+}
+
+/// The message of an error, without the usage clap adds for the command line.
+fn error_message(e: clap::Error) -> String {
+    let message = e.to_string();
+    let message = message.split("\n\nUsage:").next().unwrap_or_default();
+    message.trim_start_matches("error: ").trim_end().to_owned()
+}
+
+/// The options of the command line, on top of the ones of the file and the environment.
+fn matches() -> Result<(Command, ArgMatches), clap::Error> {
+    matches_from(std::env::args_os().collect(), |name| std::env::var(name).ok())
+}
+
+/// The options of a command line, on top of the ones of the file it names and of the environment `env` looks up.
+fn matches_from(cli: Vec<OsString>, env: impl Fn(&str) -> Option<String>) -> Result<(Command, ArgMatches), clap::Error> {
+    let command = Config::command();
+
+    // A first pass over the command line only, to find the file and the options it overrides. Missing requirements
+    // may be satisfied by the file, they are checked by the second pass.
+    let cli_matches = command.clone().ignore_errors(true).get_matches_from(&cli);
+    let on_command_line = |id: &str| matches!(cli_matches.value_source(id), Some(ValueSource::CommandLine));
+    let file = cli_matches.get_one::<std::path::PathBuf>("config");
+
+    let layered = layered_args(&command, file.map(|path| path.as_path()), env, on_command_line)
+        .map_err(|e| command.clone().error(ErrorKind::InvalidValue, e))?;
+    let args = cli.first().cloned().into_iter().chain(layered).chain(cli.iter().skip(1).cloned());
+    let matches = command.clone().try_get_matches_from(args)?;
+    Ok((command, matches))
+}
+
+pub(crate) fn load(path: &Path) -> std::io::Result<Config> {
+    use std::io::{Error, ErrorKind::InvalidData};
+    let command = Config::command();
+    let layered = layered_args(&command, Some(path), |name| std::env::var(name).ok(), |_| false).map_err(|e| Error::new(InvalidData, e))?;
+    let args = std::iter::once(OsString::from(crate::LIB_NAME)).chain(layered);
+    let matches = command
+        .try_get_matches_from(args)
+        .map_err(|e| Error::new(InvalidData, e.to_string()))?;
+    Config::from_arg_matches(&matches).map_err(|e| Error::new(InvalidData, e.to_string()))
+}
+
+/// The arguments of the file and of the environment, without the options given on the command line, nor the options
+/// of the file that are in the environment.
+fn layered_args(
+    command: &Command,
+    file: Option<&Path>,
+    env: impl Fn(&str) -> Option<String>,
+    on_command_line: impl Fn(&str) -> bool,
+) -> Result<Vec<OsString>, String> {
+    let env = env_args(command, env)?;
+    let file = match file {
+        Some(path) => file_args(command, path).map_err(|e| format!("config file {} error \"{}\"", path.display(), e))?,
+        None => Vec::new(),
+    };
+    let in_env = env.iter().map(|option| option.id.clone()).collect::<Vec<_>>();
+    let file = file.into_iter().filter(|option| !in_env.contains(&option.id));
+    Ok(file
+        .chain(env)
+        .filter(|option| !on_command_line(&option.id))
+        .flat_map(|option| option.args)
+        .collect())
+}
+
+fn file_args(command: &Command, path: &Path) -> Result<Vec<Layered>, String> {
+    let table = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())?
+        .parse::<Table>()
+        .map_err(|e| e.to_string())?;
+    let mut options = Vec::new();
+    for (key, value) in &table {
+        match value {
+            Value::Table(section) => {
+                for (key_in_section, value) in section {
+                    let alias = SECTION_ALIASES
+                        .iter()
+                        .find(|(section, alias, _)| section == key && alias == key_in_section)
+                        .map(|(_, _, option)| option.to_string());
+                    let name = alias.unwrap_or_else(|| format!("{key}_{key_in_section}"));
+                    let arg = find_arg(command, &name).ok_or_else(|| format!("unknown option `{key}.{key_in_section}`"))?;
+                    options.push(option_args(arg, value).map_err(|e| format!("`{key}.{key_in_section}` {e}"))?);
+                }
+            }
+            // Arrays of sections, such as several upstreams, which an instance does not have.
+            Value::Array(sections) if !sections.is_empty() && sections.iter().all(Value::is_table) => {
+                return Err(format!("`[[{key}]]` is not supported, a section is given once, as `[{key}]`"));
+            }
+            value => {
+                let arg = find_arg(command, key).ok_or_else(|| format!("unknown option `{key}`"))?;
+                options.push(option_args(arg, value).map_err(|e| format!("`{key}` {e}"))?);
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn env_args(command: &Command, env: impl Fn(&str) -> Option<String>) -> Result<Vec<Layered>, String> {
+    let mut options = Vec::new();
+    for arg in layerable_args(command) {
+        let name = format!("{ENV_PREFIX}{}", arg.get_id().as_str().to_uppercase());
+        let Some(value) = env(&name) else {
+            continue;
+        };
+        let value = match arg.get_action() {
+            ArgAction::SetTrue => match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Value::Boolean(true),
+                "" | "0" | "false" | "no" | "off" => Value::Boolean(false),
+                _ => return Err(format!("{name} expects a boolean, not `{value}`")),
+            },
+            ArgAction::Append => Value::Array(value.split(',').map(|value| Value::String(value.trim().to_owned())).collect()),
+            _ => Value::String(value),
+        };
+        options.push(option_args(arg, &value).map_err(|e| format!("{name} {e}"))?);
+    }
+    Ok(options)
+}
+
+/// The options that can be set by the file and the environment.
+fn layerable_args(command: &Command) -> impl Iterator<Item = &Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| !COMMAND_LINE_ONLY.contains(&arg.get_id().as_str()))
+}
+
+/// The option a key stands for, by its field name or by its long flag.
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    let key = key.replace('-', "_");
+    layerable_args(command).find(|arg| arg.get_id() == key.as_str() || arg.get_long().is_some_and(|long| long.replace('-', "_") == key))
+}
+
+fn option_args(arg: &Arg, value: &Value) -> Result<Layered, String> {
+    let flag = OsString::from(format!("--{}", arg.get_long().unwrap_or(arg.get_id().as_str())));
+    let args = match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(true)) => vec![flag],
+        (ArgAction::SetTrue, Value::Boolean(false)) => Vec::new(),
+        (ArgAction::SetTrue, _) => return Err("expects a boolean".to_owned()),
+        (ArgAction::Append, Value::Array(values)) => {
+            let mut args = Vec::new();
+            for value in values {
+                args.push(flag.clone());
+                args.push(scalar(value)?.into());
+            }
+            args
+        }
+        (_, Value::Array(_)) => return Err("expects a single value".to_owned()),
+        (_, value) => vec![flag, scalar(value)?.into()],
+    };
+    Ok(Layered {
+        id: arg.get_id().to_string(),
+        args,
+    })
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        Value::Datetime(value) => Ok(value.to_string()),
+        Value::Array(_) | Value::Table(_) => Err("expects a string or a number".to_owned()),
+    }
+}
+
+/// The effective configuration as a TOML file, that can be given back to `--config` once its secrets, redacted,
+/// are filled in again.
+fn effective_config(command: &Command, matches: &ArgMatches) -> String {
+    let mut table = Table::new();
+    for arg in layerable_args(command) {
+        let id = arg.get_id().as_str();
+        let values = matches
+            .get_raw(id)
+            .map(|values| values.map(|value| value.to_string_lossy().into_owned()).collect::<Vec<_>>());
+        let value = match (arg.get_action(), values) {
+            (ArgAction::SetTrue, _) => Value::Boolean(matches.get_flag(id)),
+            (ArgAction::Append, values) => Value::Array(values.unwrap_or_default().into_iter().map(|value| typed(arg, value)).collect()),
+            (_, Some(values)) => match values.into_iter().next() {
+                Some(value) => typed(arg, value),
+                None => continue,
+            },
+            (_, None) => continue,
+        };
+        table.insert(id.to_owned(), value);
+    }
+    redact(&mut table);
+    toml::to_string(&table).unwrap_or_default()
+}
+
+/// A value of an option as the type the option is declared with, an integer for the integer options and a string
+/// for the others.
+fn typed(arg: &Arg, value: String) -> Value {
+    use std::any::TypeId;
+    let type_id = arg.get_value_parser().type_id();
+    let integers = [TypeId::of::<u8>(), TypeId::of::<u32>(), TypeId::of::<u64>(), TypeId::of::<usize>()];
+    match integers.iter().any(|integer| type_id == *integer) {
+        true => value.parse().map(Value::Integer).unwrap_or(Value::String(value)),
+        false => Value::String(value),
+    }
+}
+
+/// A configuration as a TOML file, like the one of `--check-config` but built from the parsed options, with the
+/// proxy password and the admin token redacted.
+pub(crate) fn redacted_toml(config: &Config) -> String {
+    fn string(value: impl ToString) -> Value {
+        Value::String(value.to_string())
+    }
+    fn strings<T: ToString>(values: &[T]) -> Value {
+        Value::Array(values.iter().map(|value| string(value.to_string())).collect())
+    }
+    fn paths(values: &[std::path::PathBuf]) -> Value {
+        Value::Array(values.iter().map(|path| string(path.display())).collect())
+    }
+    fn integer<T: TryInto<i64>>(value: T) -> Value {
+        Value::Integer(value.try_into().unwrap_or(i64::MAX))
+    }
+    fn choice<T: clap::ValueEnum>(value: &T) -> Value {
+        string(
+            value
+                .to_possible_value()
+                .map(|value| value.get_name().to_owned())
+                .unwrap_or_default(),
+        )
+    }
+
+    let c = config;
+    let values = [
+        ("listen_addr", Some(string(c.listen_addr))),
+        ("unix_listen", c.unix_listen.as_ref().map(|path| string(path.display()))),
+        ("dns_remote_server", Some(string(&c.dns_remote_server))),
+        ("socks5_settings", Some(string(&c.socks5_settings))),
+        ("force_tcp", Some(Value::Boolean(c.force_tcp))),
+        ("socks_resolve", Some(Value::Boolean(c.socks_resolve))),
+        ("socks_resolve_fallback", Some(Value::Boolean(c.socks_resolve_fallback))),
+        ("bogus_nxdomain", Some(strings(&c.bogus_nxdomain))),
+        ("bogus_retry_tcp", Some(Value::Boolean(c.bogus_retry_tcp))),
+        ("rebind_protection", Some(Value::Boolean(c.rebind_protection))),
+        ("rebind_allow", Some(strings(&c.rebind_allow))),
+        ("rebind_action", Some(choice(&c.rebind_action))),
+        ("filter_aaaa", Some(Value::Boolean(c.filter_aaaa))),
+        ("filter_aaaa_domain", Some(strings(&c.filter_aaaa_domain))),
+        ("strip_ipv6_hint", Some(Value::Boolean(c.strip_ipv6_hint))),
+        ("refuse_any", Some(Value::Boolean(c.refuse_any))),
+        ("dns64", Some(Value::Boolean(c.dns64))),
+        ("dns64_prefix", Some(string(c.dns64_prefix))),
+        ("dns64_exclude", Some(strings(&c.dns64_exclude))),
+        ("ecs", Some(choice(&c.ecs))),
+        ("ecs_subnet", c.ecs_subnet.map(string)),
+        ("dnssec", Some(Value::Boolean(c.dnssec))),
+        (
+            "dnssec_trust_anchor",
+            c.dnssec_trust_anchor.as_ref().map(|path| string(path.display())),
+        ),
+        ("cache_records", Some(Value::Boolean(c.cache_records))),
+        ("verbosity", Some(choice(&c.verbosity))),
+        ("timeout", Some(integer(c.timeout))),
+        ("allow", Some(strings(&c.allow))),
+        ("deny", Some(strings(&c.deny))),
+        ("acl_action", Some(choice(&c.acl_action))),
+        ("rate_limit", c.rate_limit.map(integer)),
+        ("rate_limit_burst", c.rate_limit_burst.map(integer)),
+        ("rate_limit_ipv4_prefix", Some(integer(c.rate_limit_ipv4_prefix))),
+        ("rate_limit_ipv6_prefix", Some(integer(c.rate_limit_ipv6_prefix))),
+        ("rrl", c.rrl.map(integer)),
+        ("rrl_slip", Some(integer(c.rrl_slip))),
+        ("max_concurrent_queries", Some(integer(c.max_concurrent_queries))),
+        ("max_tcp_clients", Some(integer(c.max_tcp_clients))),
+        ("max_udp_queries", Some(integer(c.max_udp_queries))),
+        ("queue_timeout", Some(integer(c.queue_timeout))),
+        ("hosts_file", Some(paths(&c.hosts_file))),
+        ("static_records", Some(strings(&c.static_records))),
+        ("blocklist", Some(paths(&c.blocklist))),
+        ("allowlist", Some(paths(&c.allowlist))),
+        ("block_action", Some(choice(&c.block_action))),
+        ("blocklist_reload", Some(integer(c.blocklist_reload))),
+        ("fake_ip", Some(strings(&c.fake_ip))),
+        ("fake_ip_file", c.fake_ip_file.as_ref().map(|path| string(path.display()))),
+        ("metrics_addr", c.metrics_addr.map(string)),
+        ("query_log", c.query_log.as_ref().map(|path| string(path.display()))),
+        ("query_log_max_size", Some(integer(c.query_log_max_size))),
+        ("query_log_max_age", Some(integer(c.query_log_max_age))),
+        ("query_log_keep", Some(integer(c.query_log_keep))),
+        ("query_log_sample", Some(integer(c.query_log_sample))),
+        ("query_log_anonymize", Some(Value::Boolean(c.query_log_anonymize))),
+        ("dnstap_socket", c.dnstap_socket.as_ref().map(|path| string(path.display()))),
+        ("dnstap_file", c.dnstap_file.as_ref().map(|path| string(path.display()))),
+        ("admin_addr", c.admin_addr.map(string)),
+        ("admin_token", c.admin_token.as_ref().map(string)),
+    ];
+    let mut table = values
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_owned(), value?)))
+        .collect::<Table>();
+    redact(&mut table);
+    toml::to_string(&table).unwrap_or_default()
+}
+
+/// Replace the secrets of a configuration, the proxy password and the admin token, so it can be shown.
+fn redact(table: &mut Table) {
+    if let Some(Value::String(proxy)) = table.get_mut("socks5_settings") {
+        match url::Url::parse(proxy) {
+            Ok(mut url) if url.password().is_some_and(|password| !password.is_empty()) => {
+                _ = url.set_password(Some(REDACTED));
+                *proxy = url.to_string();
+            }
+            Ok(_) => {}
+            // Not a URL, it may still hold credentials.
+            Err(_) => *proxy = REDACTED.to_owned(),
+        }
+    }
+    if let Some(token) = table.get_mut("admin_token") {
+        *token = Value::String(REDACTED.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// The configuration of a command line, with a file holding `toml` and the environment `env`.
+    fn layered(toml: &str, env: &[(&str, &str)], cli: &[&str]) -> Result<Config, String> {
+        let path = std::env::temp_dir().join(format!("dns2socks-config-{}-{}.toml", std::process::id(), rand::random::<u32>()));
+        std::fs::write(&path, toml).unwrap();
+        let env = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        let config = ["dns2socks", "--config", path.to_str().unwrap()]
+            .into_iter()
+            .chain(cli.iter().copied());
+        let result = matches_from(config.map(OsString::from).collect(), |name| env.get(name).cloned())
+            .and_then(|(_, matches)| Config::from_arg_matches(&matches))
+            .map_err(error_message);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn defaults() {
+        let config = layered("", &[], &[]).unwrap();
+        assert_eq!(config.timeout, Config::default().timeout);
+        assert_eq!(config.dns_remote_server, Config::default().dns_remote_server);
+        assert!(!config.cache_records);
+    }
+
+    #[test]
+    fn file_over_defaults() {
+        let toml = "timeout = 7\nbogus_nxdomain = [\"192.0.2.1\", \"192.0.2.2\"]\n\n[upstream]\nserver = \"192.0.2.53:53\"\n\n[cache]\nrecords = true\n";
+        let config = layered(toml, &[], &[]).unwrap();
+        assert_eq!(config.timeout, 7);
+        assert_eq!(config.bogus_nxdomain.len(), 2);
+        assert_eq!(config.dns_remote_server.to_string(), "192.0.2.53:53");
+        assert!(config.cache_records);
+    }
+
+    #[test]
+    fn env_over_file() {
+        let toml = "timeout = 7\nqueue_timeout = 100\ncache_records = true\nbogus_nxdomain = [\"192.0.2.1\", \"192.0.2.2\"]\n";
+        let env = [
+            ("DNS2SOCKS_TIMEOUT", "8"),
+            ("DNS2SOCKS_CACHE_RECORDS", "false"),
+            ("DNS2SOCKS_BOGUS_NXDOMAIN", "192.0.2.3"),
+        ];
+        let config = layered(toml, &env, &[]).unwrap();
+        assert_eq!(config.timeout, 8);
+        assert_eq!(config.queue_timeout, 100);
+        assert!(!config.cache_records);
+        assert_eq!(config.bogus_nxdomain.len(), 1);
+    }
+
+    #[test]
+    fn command_line_over_env_and_file() {
+        let toml = "timeout = 7\nqueue_timeout = 100\nbogus_nxdomain = [\"192.0.2.1\", \"192.0.2.2\"]\n";
+        let env = [("DNS2SOCKS_TIMEOUT", "8"), ("DNS2SOCKS_QUEUE_TIMEOUT", "200")];
+        let cli = ["--timeout", "9", "--bogus-nxdomain", "192.0.2.4", "--cache-records"];
+        let config = layered(toml, &env, &cli).unwrap();
+        assert_eq!(config.timeout, 9);
+        assert_eq!(config.queue_timeout, 200);
+        assert_eq!(config.bogus_nxdomain.len(), 1);
+        assert!(config.cache_records);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(
+            layered("no_such_option = 1\n", &[], &[])
+                .unwrap_err()
+                .contains("unknown option `no_such_option`")
+        );
+        assert!(layered("timeout = \"soon\"\n", &[], &[]).is_err());
+        assert!(layered("cache_records = 1\n", &[], &[]).unwrap_err().contains("expects a boolean"));
+        assert!(
+            layered("[[upstream]]\nserver = \"192.0.2.53:53\"\n", &[], &[])
+                .unwrap_err()
+                .contains("not supported")
+        );
+        assert!(layered("", &[("DNS2SOCKS_CACHE_RECORDS", "maybe")], &[]).is_err());
+    }
+}
@@ -2,6 +2,7 @@ use hickory_proto::{
     op::{Message, MessageType, OpCode, Query, ResponseCode},
     rr::{Name, RData, RecordType},
 };
+use ipnet::IpNet;
 use std::io::{Error, ErrorKind};
 use std::{net::IpAddr, str::FromStr};
 
@@ -22,6 +23,74 @@ pub fn build_dns_query(domain: &str, query_type: RecordType, used_by_tcp: bool)
     }
 }
 
+/// Build an empty response to `query` carrying the given response code.
+pub fn build_response(query: &Message, response_code: ResponseCode) -> Message {
+    let mut message = Message::response(query.metadata.id, query.metadata.op_code);
+    message.metadata.recursion_desired = query.metadata.recursion_desired;
+    message.metadata.recursion_available = true;
+    message.metadata.checking_disabled = query.metadata.checking_disabled;
+    message.metadata.response_code = response_code;
+    message.add_queries(query.queries.iter().cloned());
+    message
+}
+
+/// Why `response` can not be the answer to `query`, such as a forged response, or `None` if it can.
+pub fn response_mismatch(query: &Message, response: &Message) -> Option<&'static str> {
+    if response.metadata.message_type != MessageType::Response {
+        return Some("not a response");
+    }
+    if response.metadata.id != query.metadata.id {
+        return Some("transaction ID mismatch");
+    }
+    // Some servers leave the question out of error responses.
+    if response.queries != query.queries && !(response.queries.is_empty() && response.metadata.response_code != ResponseCode::NoError) {
+        return Some("question mismatch");
+    }
+    None
+}
+
+/// Whether an A or AAAA answer of `message` is within one of the `bogus` networks.
+pub fn contains_bogus_ip(message: &Message, bogus: &[IpNet]) -> bool {
+    if bogus.is_empty() {
+        return false;
+    }
+    message.answers.iter().any(|answer| {
+        let addr = match &answer.data {
+            RData::A(addr) => IpAddr::V4((*addr).into()),
+            RData::AAAA(addr) => IpAddr::V6((*addr).into()),
+            _ => return false,
+        };
+        bogus.iter().any(|net| net.contains(&addr))
+    })
+}
+
+/// The mnemonic of a response code, such as NXDOMAIN.
+pub fn rcode_name(rcode: ResponseCode) -> String {
+    match rcode {
+        ResponseCode::Unknown(code) => code.to_string(),
+        rcode => format!("{rcode:?}").to_uppercase(),
+    }
+}
+
+/// Whether an address is private to a site or host: RFC 1918, loopback, link-local, unique local (ULA) or `0.0.0.0/8`.
+pub fn is_private_ip(addr: IpAddr) -> bool {
+    match addr.to_canonical() {
+        IpAddr::V4(addr) => addr.is_private() || addr.is_loopback() || addr.is_link_local() || addr.octets()[0] == 0,
+        IpAddr::V6(addr) => addr.is_loopback() || addr.is_unspecified() || addr.is_unicast_link_local() || addr.is_unique_local(),
+    }
+}
+
+/// Remove the A and AAAA answers pointing to private addresses, returns how many were removed.
+pub fn strip_private_answers(message: &mut Message) -> usize {
+    let before = message.answers.len();
+    message.answers.retain(|answer| match &answer.data {
+        RData::A(addr) => !is_private_ip(IpAddr::V4((*addr).into())),
+        RData::AAAA(addr) => !is_private_ip(IpAddr::V6((*addr).into())),
+        _ => true,
+    });
+    before - message.answers.len()
+}
+
 pub fn extract_ipaddr_from_dns_message(message: &Message) -> std::io::Result<IpAddr> {
     if message.metadata.response_code != ResponseCode::NoError {
         return Err(Error::new(ErrorKind::InvalidData, format!("{:?}", message.metadata.response_code)));
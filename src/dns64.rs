@@ -0,0 +1,126 @@
+//! DNS64 (RFC 6147): AAAA records synthesized from A records for IPv6-only clients behind NAT64.
+
+use crate::{Context, config::Config, dns, query_upstream};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{AAAA, CNAME},
+    },
+};
+use ipnet::{IpNet, Ipv6Net};
+use socks5_impl::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug)]
+pub(crate) struct Dns64 {
+    prefix: Ipv6Net,
+    exclude: Vec<IpNet>,
+}
+
+impl Dns64 {
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        config.dns64.then(|| Dns64 {
+            prefix: config.dns64_prefix,
+            exclude: config.dns64_exclude.clone(),
+        })
+    }
+
+    fn is_excluded(&self, addr: IpAddr) -> bool {
+        self.exclude.iter().any(|net| net.contains(&addr))
+    }
+
+    fn embed(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.prefix.network()) | u128::from(u32::from(addr)))
+    }
+
+    /// The IPv4 address embedded in a synthetic address, `None` for an address outside the prefix.
+    fn extract(&self, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+        self.prefix.contains(&addr).then(|| Ipv4Addr::from(u128::from(addr) as u32))
+    }
+
+    /// Whether a response to an AAAA query has no usable AAAA records, so they have to be synthesized.
+    /// AAAA records in an excluded network do not count.
+    fn needs_synthesis(&self, query: &Message, response: &Message) -> bool {
+        let Some(question) = query.queries.first() else {
+            return false;
+        };
+        if question.query_type() != RecordType::AAAA || response.metadata.response_code != ResponseCode::NoError {
+            return false;
+        }
+        !response.answers.iter().any(|answer| match &answer.data {
+            RData::AAAA(addr) => !self.is_excluded(IpAddr::V6((*addr).into())),
+            _ => false,
+        })
+    }
+
+    /// Turn the answer to an A query into the answer to the AAAA query, `None` if it has no A records to use.
+    fn synthesize(&self, query: &Message, a_response: &Message) -> Option<Message> {
+        let mut response = dns::build_response(query, ResponseCode::NoError);
+        let mut synthesized = false;
+        for answer in &a_response.answers {
+            let rdata = match &answer.data {
+                RData::A(addr) if !self.is_excluded(IpAddr::V4((*addr).into())) => {
+                    synthesized = true;
+                    RData::AAAA(AAAA(self.embed((*addr).into())))
+                }
+                RData::CNAME(_) => answer.data.clone(),
+                _ => continue,
+            };
+            response.add_answer(Record::from_rdata(answer.name.clone(), answer.ttl, rdata));
+        }
+        synthesized.then_some(response)
+    }
+
+    /// The in-addr.arpa name behind a PTR query for a synthetic address.
+    fn ptr_target(&self, query: &Message) -> Option<Name> {
+        let question = query.queries.first()?;
+        if question.query_type() != RecordType::PTR {
+            return None;
+        }
+        let net = question.name().parse_arpa_name().ok()?;
+        let (IpAddr::V6(addr), 128) = (net.addr(), net.prefix_len()) else {
+            return None;
+        };
+        Some(Name::from(self.extract(addr)?))
+    }
+}
+
+/// Replace an AAAA response without usable records by records synthesized from the A records of the name.
+pub(crate) async fn synthesize(
+    ctx: &Context,
+    dns64: &Dns64,
+    query: &Message,
+    domain: &str,
+    use_tcp: bool,
+    response: Message,
+) -> Result<Message> {
+    if !dns64.needs_synthesis(query, &response) {
+        return Ok(response);
+    }
+    let mut a_query = query.clone();
+    a_query.queries[0].set_query_type(RecordType::A);
+    let a_response = query_upstream(ctx, &a_query, domain, use_tcp).await?;
+    Ok(dns64.synthesize(query, &a_response).unwrap_or(response))
+}
+
+/// Answer a PTR query for a synthetic address with a CNAME to the PTR name of the embedded IPv4 address, followed by
+/// the answer for that name. `None` for any other query.
+pub(crate) async fn resolve_ptr(ctx: &Context, dns64: &Dns64, query: &Message, domain: &str, use_tcp: bool) -> Result<Option<Message>> {
+    let Some(target) = dns64.ptr_target(query) else {
+        return Ok(None);
+    };
+    let mut v4_query = query.clone();
+    v4_query.queries[0].set_name(target.clone());
+    let v4_response = query_upstream(ctx, &v4_query, domain, use_tcp).await?;
+
+    let mut response = dns::build_response(query, v4_response.metadata.response_code);
+    let ttl = v4_response.answers.first().map_or(0, |answer| answer.ttl);
+    response.add_answer(Record::from_rdata(
+        query.queries[0].name().clone(),
+        ttl,
+        RData::CNAME(CNAME(target)),
+    ));
+    response.add_answers(v4_response.answers);
+    Ok(Some(response))
+}
@@ -0,0 +1,1183 @@
+//! DNSSEC validation of the answers resolved through the proxy, so a SOCKS5 server tampering with them is noticed.
+//! The DS and DNSKEY records of the chain of trust are fetched through the proxy as well, up to the root trust
+//! anchor built into hickory-proto.
+//!
+//! Negative answers in a signed zone need NSEC or NSEC3 records proving that the name or the type does not exist,
+//! and so do answers synthesized from a wildcard. The authority and additional records of a secure answer that can
+//! not be validated are left out, the AD bit only vouches for what is left. Queries with the CD bit are passed through unvalidated, the client
+//! validates them itself.
+
+use crate::{Context, config::Config, dns, send_upstream};
+use data_encoding::BASE32_DNSSEC;
+use hickory_proto::{
+    dnssec::rdata::NSEC,
+    dnssec::{
+        TrustAnchors, Verifier,
+        rdata::{DNSKEY, DNSSECRData, DS, NSEC3, RRSIG},
+    },
+    op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{DNSClass, Name, RData, Record, RecordType, rdata::opt::EdnsOption},
+};
+use moka::future::Cache;
+use socks5_impl::Result;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The UDP payload size announced upstream, large enough for most signed answers.
+const EDNS_PAYLOAD: u16 = 1232;
+
+/// How long validated keys and delegations are remembered.
+const CHAIN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Extended DNS Error option code (RFC 8914).
+const EDE_OPTION: u16 = 15;
+
+/// Extended DNS Error info codes (RFC 8914) of the validation failures.
+#[derive(Debug, Clone, Copy)]
+enum Ede {
+    DnssecBogus = 6,
+    SignatureExpired = 7,
+    SignatureNotYetValid = 8,
+    DnskeyMissing = 9,
+    RrsigsMissing = 10,
+    NsecMissing = 12,
+    NetworkError = 23,
+}
+
+/// Why an answer failed validation.
+#[derive(Debug)]
+struct Failure {
+    ede: Ede,
+    reason: String,
+}
+
+impl Failure {
+    fn new(ede: Ede, reason: impl Into<String>) -> Self {
+        Failure {
+            ede,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// The outcome of a successful validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Security {
+    /// Signed, with a chain of trust up to the trust anchor.
+    Secure,
+    /// In a zone proven to be unsigned.
+    Insecure,
+}
+
+/// The validated keys of a zone.
+#[derive(Debug, Clone)]
+enum ZoneKeys {
+    Secure(Arc<Vec<DNSKEY>>),
+    Insecure,
+}
+
+/// What the zone above a name says about its DS records.
+#[derive(Debug, Clone)]
+enum Delegation {
+    /// A signed delegation with these DS records.
+    Signed(Arc<Vec<DS>>),
+    /// An unsigned delegation, or a name below an unsigned zone.
+    Unsigned,
+    /// Not a delegation, the name is part of the zone above it.
+    None,
+}
+
+pub(crate) struct Validator {
+    trust_anchors: TrustAnchors,
+    keys: Cache<Name, ZoneKeys>,
+    delegations: Cache<Name, Delegation>,
+}
+
+impl Validator {
+    pub(crate) fn new(config: &Config) -> Result<Option<Self>> {
+        if !config.dnssec {
+            return Ok(None);
+        }
+        let trust_anchors = match &config.dnssec_trust_anchor {
+            Some(path) => TrustAnchors::from_file(path).map_err(|e| format!("trust anchor {} error \"{}\"", path.display(), e))?,
+            None => TrustAnchors::default(),
+        };
+        Ok(Some(Validator {
+            trust_anchors,
+            keys: Cache::builder().time_to_live(CHAIN_TTL).build(),
+            delegations: Cache::builder().time_to_live(CHAIN_TTL).build(),
+        }))
+    }
+
+    /// Answers that fail validation are replaced by a SERVFAIL, validated ones get the AD bit.
+    async fn validate(&self, ctx: &Context, query: &Message, domain: &str, mut response: Message) -> Message {
+        response.metadata.authentic_data = false;
+        if !matches!(response.metadata.response_code, ResponseCode::NoError | ResponseCode::NXDomain) {
+            return response;
+        }
+        match self.check(ctx, &response).await {
+            Ok(Security::Secure) => {
+                response.authorities = self.secure_records(ctx, &response.authorities).await;
+                response.additionals = self.secure_records(ctx, &response.additionals).await;
+                response.metadata.authentic_data = true;
+                response
+            }
+            Ok(Security::Insecure) => response,
+            Err(failure) => {
+                log::warn!("DNSSEC validation of {:?} failed \"{}\"", domain, failure.reason);
+                servfail(query, &failure)
+            }
+        }
+    }
+
+    async fn check(&self, ctx: &Context, response: &Message) -> std::result::Result<Security, Failure> {
+        let sets = rrsets(&response.answers);
+        if sets.is_empty() {
+            return self.check_denial(ctx, response).await;
+        }
+        let has_dname = response.answers.iter().any(|record| record.record_type() == RecordType::DNAME);
+        let mut security = Security::Secure;
+        for (name, record_type) in sets {
+            let sigs = signatures(&response.answers, &name, record_type);
+            if sigs.is_empty() {
+                // The CNAME synthesized from a DNAME is never signed, the DNAME is.
+                if record_type == RecordType::CNAME && has_dname {
+                    continue;
+                }
+                match self.zone_security(ctx, &name).await? {
+                    Security::Insecure => security = Security::Insecure,
+                    Security::Secure => return Err(Failure::new(Ede::RrsigsMissing, format!("{name} {record_type} is not signed"))),
+                }
+                continue;
+            }
+            match self.verified_signature(ctx, &name, record_type, &response.answers, &sigs).await? {
+                None => security = Security::Insecure,
+                // Fewer labels in the signature than in the owner name, the RRset was synthesized from a wildcard.
+                Some(labels) if labels < name.num_labels() => {
+                    let (nsec, nsec3) = self.denial_records(ctx, &response.authorities, &name).await?;
+                    match wildcard_proof(&nsec, &nsec3, &name, labels) {
+                        Some(Security::Secure) => {}
+                        Some(Security::Insecure) => security = Security::Insecure,
+                        None => {
+                            return Err(Failure::new(
+                                Ede::NsecMissing,
+                                format!("no proof that {name} is not the wildcard expansion"),
+                            ));
+                        }
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(security)
+    }
+
+    /// The RRsets of a section that are signed with a chain of trust up to the trust anchor, with their signatures.
+    async fn secure_records(&self, ctx: &Context, records: &[Record]) -> Vec<Record> {
+        let mut secure = Vec::new();
+        for (name, record_type) in rrsets(records) {
+            let sigs = signatures(records, &name, record_type);
+            if !sigs.is_empty() && matches!(self.verify(ctx, &name, record_type, records, &sigs).await, Ok(Security::Secure)) {
+                secure.push((name, record_type));
+            }
+        }
+        records
+            .iter()
+            .filter(|record| {
+                let record_type = match &record.data {
+                    RData::DNSSEC(DNSSECRData::RRSIG(sig)) => sig.input().type_covered,
+                    _ => record.record_type(),
+                };
+                secure.contains(&(record.name.clone(), record_type))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// NXDOMAIN and NODATA answers in a signed zone need NSEC or NSEC3 records proving that the name, or the type
+    /// at the name, does not exist. A signed SOA alone proves nothing.
+    async fn check_denial(&self, ctx: &Context, response: &Message) -> std::result::Result<Security, Failure> {
+        let Some(question) = response.queries.first() else {
+            return Ok(Security::Insecure);
+        };
+        let (qname, qtype) = (question.name(), question.query_type());
+        for (name, record_type) in rrsets(&response.authorities) {
+            let sigs = signatures(&response.authorities, &name, record_type);
+            if !sigs.is_empty() && !matches!(record_type, RecordType::NSEC | RecordType::NSEC3) {
+                self.verify(ctx, &name, record_type, &response.authorities, &sigs).await?;
+            }
+        }
+        let (nsec, nsec3) = self.denial_records(ctx, &response.authorities, qname).await?;
+        let proof = match response.metadata.response_code {
+            ResponseCode::NXDomain => nxdomain_proof(&nsec, &nsec3, qname),
+            _ => nodata_proof(&nsec, &nsec3, qname, qtype),
+        };
+        if let Some(security) = proof {
+            return Ok(security);
+        }
+        match self.zone_security(ctx, qname).await? {
+            Security::Insecure => Ok(Security::Insecure),
+            Security::Secure => Err(Failure::new(
+                Ede::NsecMissing,
+                format!("no proof for the negative answer for {qname} {qtype}"),
+            )),
+        }
+    }
+
+    /// The NSEC and NSEC3 records of a section that can prove something about `name`: signed by a zone `name` is
+    /// in, with a valid signature. Records of an unsigned zone prove nothing and are left out.
+    async fn denial_records<'a>(
+        &self,
+        ctx: &Context,
+        records: &'a [Record],
+        name: &Name,
+    ) -> std::result::Result<(Vec<(&'a Name, &'a NSEC)>, Vec<(&'a Name, &'a NSEC3)>), Failure> {
+        let (mut nsec, mut nsec3) = (Vec::new(), Vec::new());
+        for (owner, record_type) in rrsets(records) {
+            if !matches!(record_type, RecordType::NSEC | RecordType::NSEC3) {
+                continue;
+            }
+            let mut sigs = signatures(records, &owner, record_type);
+            sigs.retain(|sig| sig.input().signer_name.zone_of(name));
+            if sigs.is_empty() || self.verify(ctx, &owner, record_type, records, &sigs).await? == Security::Insecure {
+                continue;
+            }
+            for record in records.iter().filter(|record| record.name == owner) {
+                match &record.data {
+                    RData::DNSSEC(DNSSECRData::NSEC(data)) => nsec.push((&record.name, data)),
+                    RData::DNSSEC(DNSSECRData::NSEC3(data)) => nsec3.push((&record.name, data)),
+                    _ => {}
+                }
+            }
+        }
+        Ok((nsec, nsec3))
+    }
+
+    /// Verify an RRset with one of its signatures and the keys of the signer.
+    async fn verify(
+        &self,
+        ctx: &Context,
+        name: &Name,
+        record_type: RecordType,
+        records: &[Record],
+        sigs: &[&RRSIG],
+    ) -> std::result::Result<Security, Failure> {
+        Ok(match self.verified_signature(ctx, name, record_type, records, sigs).await? {
+            Some(_) => Security::Secure,
+            None => Security::Insecure,
+        })
+    }
+
+    /// The label count of the signature an RRset is verified with, `None` when its signer is an unsigned zone.
+    async fn verified_signature(
+        &self,
+        ctx: &Context,
+        name: &Name,
+        record_type: RecordType,
+        records: &[Record],
+        sigs: &[&RRSIG],
+    ) -> std::result::Result<Option<u8>, Failure> {
+        let mut ede = Ede::DnssecBogus;
+        for sig in sigs {
+            let signer = &sig.input().signer_name;
+            if !signer.zone_of(name) {
+                continue;
+            }
+            let keys = match Box::pin(self.zone_keys(ctx, signer)).await? {
+                ZoneKeys::Secure(keys) => keys,
+                ZoneKeys::Insecure => return Ok(None),
+            };
+            match check_signature(sig, keys.iter(), name, records) {
+                Ok(()) => return Ok(Some(sig.input().num_labels)),
+                Err(e) => ede = e,
+            }
+        }
+        Err(Failure::new(ede, format!("no valid signature for {name} {record_type}")))
+    }
+
+    /// The keys of a zone, validated with the DS records in the zone above or with the trust anchor at the root.
+    async fn zone_keys(&self, ctx: &Context, zone: &Name) -> std::result::Result<ZoneKeys, Failure> {
+        if let Some(keys) = self.keys.get(zone).await {
+            return Ok(keys);
+        }
+        let ds = match zone.is_root() {
+            true => None,
+            false => match Box::pin(self.delegation(ctx, zone)).await? {
+                Delegation::Signed(ds) => Some(ds),
+                Delegation::Unsigned => {
+                    self.keys.insert(zone.clone(), ZoneKeys::Insecure).await;
+                    return Ok(ZoneKeys::Insecure);
+                }
+                Delegation::None => return Err(Failure::new(Ede::DnskeyMissing, format!("{zone} signs records but is not a zone"))),
+            },
+        };
+
+        let response = self.fetch(ctx, zone, RecordType::DNSKEY).await?;
+        let dnskeys = response
+            .answers
+            .iter()
+            .filter(|record| record.name == *zone)
+            .filter_map(|record| match &record.data {
+                RData::DNSSEC(DNSSECRData::DNSKEY(key)) => Some(key.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let trusted = dnskeys
+            .iter()
+            .filter(|key| match &ds {
+                Some(ds) => ds.iter().any(|ds| ds.covers(zone, key).unwrap_or(false)),
+                None => self.trust_anchors.contains(key.public_key()),
+            })
+            .collect::<Vec<_>>();
+        if trusted.is_empty() {
+            // A zone signed with algorithms that are not supported is treated as unsigned, RFC 4035 section 5.2.
+            if let Some(ds) = &ds
+                && !ds.iter().any(|ds| ds.algorithm().is_supported() && ds.digest_type().is_supported())
+            {
+                self.keys.insert(zone.clone(), ZoneKeys::Insecure).await;
+                return Ok(ZoneKeys::Insecure);
+            }
+            return Err(Failure::new(
+                Ede::DnskeyMissing,
+                format!("no DNSKEY of {zone} matches its DS records or trust anchor"),
+            ));
+        }
+
+        let mut result = Err(Ede::RrsigsMissing);
+        for sig in signatures(&response.answers, zone, RecordType::DNSKEY) {
+            result = check_signature(sig, trusted.iter().copied(), zone, &response.answers);
+            if result.is_ok() {
+                break;
+            }
+        }
+        if let Err(ede) = result {
+            return Err(Failure::new(
+                ede,
+                format!("DNSKEY records of {zone} are not signed by a trusted key"),
+            ));
+        }
+        let keys = ZoneKeys::Secure(Arc::new(dnskeys));
+        self.keys.insert(zone.clone(), keys.clone()).await;
+        Ok(keys)
+    }
+
+    /// Fetch the DS records of a name below the root, with the proof from the zone above if there are none.
+    async fn delegation(&self, ctx: &Context, name: &Name) -> std::result::Result<Delegation, Failure> {
+        if let Some(delegation) = self.delegations.get(name).await {
+            return Ok(delegation);
+        }
+        let response = self.fetch(ctx, name, RecordType::DS).await?;
+        let ds = response
+            .answers
+            .iter()
+            .filter(|record| record.name == *name)
+            .filter_map(|record| match &record.data {
+                RData::DNSSEC(DNSSECRData::DS(ds)) => Some(ds.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let delegation = if ds.is_empty() {
+            self.denied_delegation(ctx, name, &response).await?
+        } else {
+            let sigs = signatures_from_above(&response.answers, name, RecordType::DS, name);
+            match self.verify(ctx, name, RecordType::DS, &response.answers, &sigs).await? {
+                Security::Secure => Delegation::Signed(Arc::new(ds)),
+                Security::Insecure => Delegation::Unsigned,
+            }
+        };
+        self.delegations.insert(name.clone(), delegation.clone()).await;
+        Ok(delegation)
+    }
+
+    /// Tell an unsigned delegation from a name inside a zone, with the NSEC or NSEC3 records proving that a name has
+    /// no DS records.
+    async fn denied_delegation(&self, ctx: &Context, name: &Name, response: &Message) -> std::result::Result<Delegation, Failure> {
+        let mut delegation = None;
+        for (owner, record_type) in rrsets(&response.authorities) {
+            if !matches!(record_type, RecordType::NSEC | RecordType::NSEC3) {
+                continue;
+            }
+            let sigs = signatures_from_above(&response.authorities, &owner, record_type, name);
+            if sigs.is_empty() {
+                continue;
+            }
+            if self.verify(ctx, &owner, record_type, &response.authorities, &sigs).await? == Security::Insecure {
+                return Ok(Delegation::Unsigned);
+            }
+            for record in response.authorities.iter().filter(|record| record.name == owner) {
+                let proof = match &record.data {
+                    RData::DNSSEC(DNSSECRData::NSEC(nsec)) if owner == *name => {
+                        Some((nsec.type_set().contains(RecordType::NS), nsec.type_set().contains(RecordType::DS)))
+                    }
+                    RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => nsec3_proof(&owner, nsec3, name),
+                    _ => None,
+                };
+                match proof {
+                    Some((_, true)) => return Err(Failure::new(Ede::DnssecBogus, format!("{name} has DS records that are missing"))),
+                    Some((is_delegation, false)) => {
+                        delegation = Some(match is_delegation {
+                            true => Delegation::Unsigned,
+                            false => Delegation::None,
+                        })
+                    }
+                    None => {}
+                }
+            }
+        }
+        if let Some(delegation) = delegation {
+            return Ok(delegation);
+        }
+        if response.metadata.response_code == ResponseCode::NXDomain && !response.authorities.is_empty() {
+            // The name does not exist, a wrong NXDOMAIN can only make an answer fail validation.
+            return Ok(Delegation::None);
+        }
+        match Box::pin(self.zone_security(ctx, &name.base_name())).await? {
+            Security::Insecure => Ok(Delegation::Unsigned),
+            Security::Secure => Err(Failure::new(Ede::NsecMissing, format!("no proof that {name} has no DS records"))),
+        }
+    }
+
+    /// Whether the zone of a name is signed, by following the delegations from the root down to it.
+    async fn zone_security(&self, ctx: &Context, name: &Name) -> std::result::Result<Security, Failure> {
+        for labels in 1..=name.num_labels() {
+            let ancestor = name.trim_to(labels as usize);
+            if let Delegation::Unsigned = Box::pin(self.delegation(ctx, &ancestor)).await? {
+                return Ok(Security::Insecure);
+            }
+        }
+        Ok(Security::Secure)
+    }
+
+    /// Query the upstream for a record of the chain of trust.
+    async fn fetch(&self, ctx: &Context, name: &Name, record_type: RecordType) -> std::result::Result<Message, Failure> {
+        let mut query = Message::new(rand::random::<u16>(), MessageType::Query, OpCode::Query);
+        query.metadata.recursion_desired = true;
+        query.add_query(Query::query(name.clone(), record_type));
+        let query = with_dnssec_ok(&query);
+        let domain = name.to_ascii();
+        send_upstream(ctx, &query, &domain, true)
+            .await
+            .map_err(|e| Failure::new(Ede::NetworkError, format!("fetching {name} {record_type} {e}")))
+    }
+}
+
+/// Resolve a query with the DO bit set and validate the answer, unless the client set the CD bit to validate it
+/// itself.
+pub(crate) async fn resolve(ctx: &Context, validator: &Validator, query: &Message, domain: &str, use_tcp: bool) -> Result<Message> {
+    let checking_disabled = query.metadata.checking_disabled;
+    let query = with_dnssec_ok(query);
+    let mut response = send_upstream(ctx, &query, domain, use_tcp).await?;
+    if response.metadata.truncation && !use_tcp {
+        response = send_upstream(ctx, &query, domain, true).await?;
+    }
+    if checking_disabled {
+        response.metadata.authentic_data = false;
+        return Ok(response);
+    }
+    Ok(validator.validate(ctx, &query, domain, response).await)
+}
+
+/// Whether the answer to a query can be cached: unvalidated answers to CD queries are only for that client.
+pub(crate) fn cacheable(ctx: &Context, query: &Message) -> bool {
+    ctx.dnssec.is_none() || !query.metadata.checking_disabled
+}
+
+/// Whether a response is a validation failure, which is not cached: a failure to fetch the chain of trust through
+/// the proxy may be gone with the next query.
+pub(crate) fn is_failure(response: &Message) -> bool {
+    response.metadata.response_code == ResponseCode::ServFail
+        && response
+            .edns
+            .as_ref()
+            .is_some_and(|edns| edns.options().as_ref().iter().any(|(code, _)| u16::from(*code) == EDE_OPTION))
+}
+
+/// Fit a validated response to the query of the client: the DNSSEC records and the AD bit only go to clients that
+/// asked for them, RFC 4035 section 3.2.1 and RFC 6840 section 5.7.
+pub(crate) fn on_response(query: &Message, mut response: Message) -> Message {
+    response.metadata.checking_disabled = query.metadata.checking_disabled;
+    let dnssec_ok = query.edns.as_ref().is_some_and(|edns| edns.flags().dnssec_ok);
+    if !dnssec_ok {
+        let query_type = query.queries.first().map(|question| question.query_type());
+        let wanted = |record: &Record| {
+            let record_type = record.record_type();
+            !matches!(record_type, RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3) || Some(record_type) == query_type
+        };
+        response.answers.retain(wanted);
+        response.authorities.retain(wanted);
+        response.additionals.retain(wanted);
+        response.metadata.authentic_data &= query.metadata.authentic_data;
+    }
+    if query.edns.is_none() {
+        response.edns = None;
+    }
+    response
+}
+
+/// The query to send upstream, asking for the DNSSEC records and for answers the upstream considers bogus, which
+/// are validated here.
+fn with_dnssec_ok(query: &Message) -> Message {
+    let mut query = query.clone();
+    query.metadata.checking_disabled = true;
+    let edns = query.edns.get_or_insert_with(Edns::new);
+    edns.set_dnssec_ok(true);
+    if edns.max_payload() < EDNS_PAYLOAD {
+        edns.set_max_payload(EDNS_PAYLOAD);
+    }
+    query
+}
+
+/// A SERVFAIL with the reason of the failure as Extended DNS Error.
+fn servfail(query: &Message, failure: &Failure) -> Message {
+    let mut response = dns::build_response(query, ResponseCode::ServFail);
+    let mut edns = Edns::new();
+    edns.set_max_payload(EDNS_PAYLOAD);
+    let info = [(failure.ede as u16).to_be_bytes().as_slice(), failure.reason.as_bytes()].concat();
+    edns.options_mut().insert(EdnsOption::Unknown(EDE_OPTION, info));
+    response.set_edns(edns);
+    response
+}
+
+/// Check one signature of an RRset against the keys of its signer.
+fn check_signature<'a>(
+    sig: &RRSIG,
+    keys: impl Iterator<Item = &'a DNSKEY>,
+    name: &Name,
+    records: &[Record],
+) -> std::result::Result<(), Ede> {
+    let input = sig.input();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as u32);
+    // Serial number arithmetic, RFC 1982, the signature times wrap around in 2106.
+    let before = |a: u32, b: u32| (a.wrapping_sub(b) as i32) < 0;
+    if before(now, input.sig_inception.get()) {
+        return Err(Ede::SignatureNotYetValid);
+    }
+    if before(input.sig_expiration.get(), now) {
+        return Err(Ede::SignatureExpired);
+    }
+    let mut keys = keys
+        .filter(|key| key.zone_key() && !key.revoke() && key.algorithm() == input.algorithm)
+        .filter(|key| key.calculate_key_tag().ok() == Some(input.key_tag));
+    match keys.any(|key| key.verify_rrsig(name, DNSClass::IN, sig, records.iter()).is_ok()) {
+        true => Ok(()),
+        false => Err(Ede::DnssecBogus),
+    }
+}
+
+/// Whether an NSEC3 record is about a name, as `(is a delegation, has DS records)`. A covering record with the
+/// opt-out flag stands for unsigned delegations.
+fn nsec3_proof(owner: &Name, nsec3: &NSEC3, name: &Name) -> Option<(bool, bool)> {
+    if nsec3_matches(owner, nsec3, name) {
+        let types = nsec3.type_set();
+        return Some((types.contains(RecordType::NS), types.contains(RecordType::DS)));
+    }
+    (nsec3_covers(owner, nsec3, name) && nsec3.opt_out()).then_some((true, false))
+}
+
+/// The owner hash of an NSEC3 record and the hash of `name` with its parameters, when `name` is in its zone.
+fn nsec3_hashes(owner: &Name, nsec3: &NSEC3, name: &Name) -> Option<(Vec<u8>, Vec<u8>)> {
+    if !owner.base_name().zone_of(name) {
+        return None;
+    }
+    let owner_hash = BASE32_DNSSEC.decode(owner.iter().next()?).ok()?;
+    let hash = nsec3.hash_algorithm().hash(nsec3.salt(), name, nsec3.iterations()).ok()?;
+    Some((owner_hash, hash.as_ref().to_vec()))
+}
+
+/// Whether an NSEC3 record is the one of `name`.
+fn nsec3_matches(owner: &Name, nsec3: &NSEC3, name: &Name) -> bool {
+    nsec3_hashes(owner, nsec3, name).is_some_and(|(owner_hash, hash)| owner_hash == hash)
+}
+
+/// Whether an NSEC3 record proves that `name` does not exist, its hash falling between the owner and the next one.
+fn nsec3_covers(owner: &Name, nsec3: &NSEC3, name: &Name) -> bool {
+    let Some((owner_hash, hash)) = nsec3_hashes(owner, nsec3, name) else {
+        return false;
+    };
+    let (owner_hash, hash, next) = (owner_hash.as_slice(), hash.as_slice(), nsec3.next_hashed_owner_name());
+    match owner_hash < next {
+        true => owner_hash < hash && hash < next,
+        // The last record of the zone wraps around to the first.
+        false => owner_hash < hash || hash < next,
+    }
+}
+
+/// Whether an NSEC record proves that `name` does not exist, falling between the owner and the next name in the
+/// canonical order.
+fn nsec_covers(owner: &Name, nsec: &NSEC, name: &Name) -> bool {
+    let next = nsec.next_domain_name();
+    match owner < next {
+        true => owner < name && name < next,
+        // The last record of the zone wraps around to the apex.
+        false => owner < name && next.zone_of(name),
+    }
+}
+
+/// The closest encloser of a name that does not exist, as proven by NSEC3 records (RFC 5155 section 8.3): the
+/// longest ancestor with a matching record whose child towards `name`, the next closer name, is covered. Also tells
+/// whether the covering record has the opt-out flag.
+fn nsec3_closest_encloser(nsec3: &[(&Name, &NSEC3)], name: &Name) -> Option<(Name, bool)> {
+    (0..name.num_labels()).rev().find_map(|labels| {
+        let encloser = name.trim_to(labels as usize);
+        let next_closer = name.trim_to(labels as usize + 1);
+        nsec3.iter().find(|(owner, data)| nsec3_matches(owner, data, &encloser))?;
+        let (_, covering) = nsec3.iter().find(|(owner, data)| nsec3_covers(owner, data, &next_closer))?;
+        Some((encloser, covering.opt_out()))
+    })
+}
+
+/// The security of the proof that `name` does not exist, `None` without one. The wildcard at its closest encloser
+/// must not exist either.
+fn nxdomain_proof(nsec: &[(&Name, &NSEC)], nsec3: &[(&Name, &NSEC3)], name: &Name) -> Option<Security> {
+    if let Some((owner, data)) = nsec.iter().find(|(owner, data)| nsec_covers(owner, data, name)) {
+        // The closest encloser is the longest ancestor of the name the covering record is in, or points to.
+        let encloser = (0..name.num_labels())
+            .rev()
+            .map(|labels| name.trim_to(labels as usize))
+            .find(|ancestor| ancestor.zone_of(owner) || ancestor.zone_of(data.next_domain_name()))?;
+        let wildcard = encloser.prepend_label("*").ok()?;
+        return nsec
+            .iter()
+            .any(|(owner, data)| nsec_covers(owner, data, &wildcard))
+            .then_some(Security::Secure);
+    }
+    let (encloser, opt_out) = nsec3_closest_encloser(nsec3, name)?;
+    let wildcard = encloser.prepend_label("*").ok()?;
+    nsec3.iter().find(|(owner, data)| nsec3_covers(owner, data, &wildcard))?;
+    Some(if opt_out { Security::Insecure } else { Security::Secure })
+}
+
+/// The security of the proof that `name` has no records of type `record_type`, `None` without one.
+fn nodata_proof(nsec: &[(&Name, &NSEC)], nsec3: &[(&Name, &NSEC3)], name: &Name, record_type: RecordType) -> Option<Security> {
+    let lacks = |types: &hickory_proto::rr::RecordTypeSet| !types.contains(record_type) && !types.contains(RecordType::CNAME);
+    // The name exists without the type.
+    if nsec.iter().any(|(owner, data)| *owner == name && lacks(data.type_set())) {
+        return Some(Security::Secure);
+    }
+    if nsec3
+        .iter()
+        .any(|(owner, data)| nsec3_matches(owner, data, name) && lacks(data.type_set()))
+    {
+        return Some(Security::Secure);
+    }
+    // An empty non-terminal, the name only exists for the names below it.
+    if nsec.iter().any(|(owner, data)| {
+        let next = data.next_domain_name();
+        nsec_covers(owner, data, name) && name.zone_of(next) && next != name
+    }) {
+        return Some(Security::Secure);
+    }
+    // The name does not exist, and the wildcard that would have answered has no records of the type.
+    let lacking_wildcard =
+        |owner: &Name, types: &hickory_proto::rr::RecordTypeSet| owner.is_wildcard() && owner.base_name().zone_of(name) && lacks(types);
+    if nsec.iter().any(|(owner, data)| nsec_covers(owner, data, name))
+        && nsec.iter().any(|(owner, data)| lacking_wildcard(owner, data.type_set()))
+    {
+        return Some(Security::Secure);
+    }
+    let (encloser, opt_out) = nsec3_closest_encloser(nsec3, name)?;
+    // A DS query for an unsigned delegation covered by an opt-out record.
+    if opt_out && record_type == RecordType::DS {
+        return Some(Security::Insecure);
+    }
+    let wildcard = encloser.prepend_label("*").ok()?;
+    nsec3
+        .iter()
+        .any(|(owner, data)| nsec3_matches(owner, data, &wildcard) && lacks(data.type_set()))
+        .then_some(if opt_out { Security::Insecure } else { Security::Secure })
+}
+
+/// The security of the proof that an answer synthesized from the wildcard with `labels` labels was the right one:
+/// the name itself, or rather the next closer name below the wildcard's parent, does not exist.
+fn wildcard_proof(nsec: &[(&Name, &NSEC)], nsec3: &[(&Name, &NSEC3)], name: &Name, labels: u8) -> Option<Security> {
+    if nsec.iter().any(|(owner, data)| nsec_covers(owner, data, name)) {
+        return Some(Security::Secure);
+    }
+    let next_closer = name.trim_to(labels as usize + 1);
+    let (_, covering) = nsec3.iter().find(|(owner, data)| nsec3_covers(owner, data, &next_closer))?;
+    Some(if covering.opt_out() { Security::Insecure } else { Security::Secure })
+}
+
+/// The owner names and types of the RRsets in a section, the signatures aside.
+fn rrsets(records: &[Record]) -> Vec<(Name, RecordType)> {
+    let mut sets = Vec::new();
+    for record in records {
+        let set = (record.name.clone(), record.record_type());
+        if set.1 != RecordType::RRSIG && !sets.contains(&set) {
+            sets.push(set);
+        }
+    }
+    sets
+}
+
+/// The signatures of an RRset.
+fn signatures<'a>(records: &'a [Record], name: &Name, record_type: RecordType) -> Vec<&'a RRSIG> {
+    records
+        .iter()
+        .filter(|record| record.name == *name)
+        .filter_map(|record| match &record.data {
+            RData::DNSSEC(DNSSECRData::RRSIG(sig)) if sig.input().type_covered == record_type => Some(sig),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The signatures of the records about a name that were made by a zone above it. Anything else can not prove
+/// anything about the delegation of the name, and would make the chain of trust loop.
+fn signatures_from_above<'a>(records: &'a [Record], owner: &Name, record_type: RecordType, name: &Name) -> Vec<&'a RRSIG> {
+    let mut sigs = signatures(records, owner, record_type);
+    sigs.retain(|sig| {
+        let signer = &sig.input().signer_name;
+        signer.zone_of(name) && signer.num_labels() < name.num_labels()
+    });
+    sigs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Resolver, Upstream};
+    use hickory_proto::{
+        dnssec::{Algorithm, DigestType, Nsec3HashAlgorithm, SigningKey, TBS, crypto::EcdsaSigningKey, rdata::sig::SigInput},
+        rr::{SerialNumber, rdata::A, rdata::SOA},
+    };
+    use std::{collections::HashMap, str::FromStr};
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn refs<T>(records: &[(Name, T)]) -> Vec<(&Name, &T)> {
+        records.iter().map(|(owner, data)| (owner, data)).collect()
+    }
+
+    fn without<T: Clone>(records: &[(Name, T)], owner: &str) -> Vec<(Name, T)> {
+        records.iter().filter(|(name, _)| *name != self::name(owner)).cloned().collect()
+    }
+
+    /// The NSEC chain of a zone with these names and types.
+    fn nsec_chain(names: &[(&str, &[RecordType])]) -> Vec<(Name, NSEC)> {
+        let mut names = names.iter().map(|(owner, types)| (name(owner), types.to_vec())).collect::<Vec<_>>();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        (0..names.len())
+            .map(|i| {
+                let next = names[(i + 1) % names.len()].0.clone();
+                (names[i].0.clone(), NSEC::new_cover_self(next, names[i].1.iter().copied()))
+            })
+            .collect()
+    }
+
+    /// The NSEC3 chain of a zone with these names and types, without salt nor additional iterations.
+    fn nsec3_chain(zone: &str, names: &[(&str, &[RecordType])], opt_out: bool) -> Vec<(Name, NSEC3)> {
+        let hash = |owner: &str| Nsec3HashAlgorithm::SHA1.hash(&[], &name(owner), 0).unwrap().as_ref().to_vec();
+        let mut hashes = names.iter().map(|(owner, types)| (hash(owner), types.to_vec())).collect::<Vec<_>>();
+        hashes.sort();
+        (0..hashes.len())
+            .map(|i| {
+                let owner = name(zone).prepend_label(BASE32_DNSSEC.encode(&hashes[i].0).as_str()).unwrap();
+                let next = hashes[(i + 1) % hashes.len()].0.clone();
+                let nsec3 = NSEC3::new(Nsec3HashAlgorithm::SHA1, opt_out, 0, Vec::new(), next, hashes[i].1.iter().copied());
+                (owner, nsec3)
+            })
+            .collect()
+    }
+
+    /// `y.example.` and `w.example.` are empty non-terminals.
+    const ZONE: &[(&str, &[RecordType])] = &[
+        ("example.", &[RecordType::SOA, RecordType::NS, RecordType::DNSKEY]),
+        ("a.example.", &[RecordType::A]),
+        ("c.example.", &[RecordType::A, RecordType::TXT]),
+        ("*.w.example.", &[RecordType::A]),
+        ("x.y.example.", &[RecordType::A]),
+    ];
+
+    /// NSEC3 has records for the empty non-terminals as well.
+    const NSEC3_ZONE: &[(&str, &[RecordType])] = &[
+        ("example.", &[RecordType::SOA, RecordType::NS, RecordType::DNSKEY]),
+        ("a.example.", &[RecordType::A]),
+        ("w.example.", &[]),
+        ("*.w.example.", &[RecordType::A]),
+        ("y.example.", &[]),
+        ("x.y.example.", &[RecordType::A]),
+    ];
+
+    #[test]
+    fn nsec_nxdomain() {
+        let chain = nsec_chain(ZONE);
+        assert_eq!(nxdomain_proof(&refs(&chain), &[], &name("b.example.")), Some(Security::Secure));
+        // The wildcard at the closest encloser, *.example., is covered by the record of the apex.
+        assert_eq!(nxdomain_proof(&refs(&without(&chain, "example.")), &[], &name("b.example.")), None);
+        assert_eq!(
+            nxdomain_proof(&refs(&without(&chain, "a.example.")), &[], &name("b.example.")),
+            None
+        );
+        assert_eq!(nxdomain_proof(&refs(&chain), &[], &name("c.example.")), None);
+    }
+
+    #[test]
+    fn nsec_nodata() {
+        let chain = nsec_chain(ZONE);
+        assert_eq!(
+            nodata_proof(&refs(&chain), &[], &name("c.example."), RecordType::MX),
+            Some(Security::Secure)
+        );
+        assert_eq!(nodata_proof(&refs(&chain), &[], &name("c.example."), RecordType::TXT), None);
+        assert_eq!(
+            nodata_proof(&refs(&without(&chain, "c.example.")), &[], &name("c.example."), RecordType::MX),
+            None
+        );
+        // An empty non-terminal.
+        assert_eq!(
+            nodata_proof(&refs(&chain), &[], &name("y.example."), RecordType::A),
+            Some(Security::Secure)
+        );
+        // A wildcard without the type.
+        assert_eq!(
+            nodata_proof(&refs(&chain), &[], &name("z.w.example."), RecordType::MX),
+            Some(Security::Secure)
+        );
+        assert_eq!(nodata_proof(&refs(&chain), &[], &name("z.w.example."), RecordType::A), None);
+    }
+
+    #[test]
+    fn nsec_wildcard() {
+        let chain = nsec_chain(ZONE);
+        assert_eq!(wildcard_proof(&refs(&chain), &[], &name("q.w.example."), 2), Some(Security::Secure));
+        assert_eq!(
+            wildcard_proof(&refs(&without(&chain, "*.w.example.")), &[], &name("q.w.example."), 2),
+            None
+        );
+    }
+
+    #[test]
+    fn nsec3_nxdomain() {
+        let chain = nsec3_chain("example.", NSEC3_ZONE, false);
+        assert_eq!(nxdomain_proof(&[], &refs(&chain), &name("b.example.")), Some(Security::Secure));
+        assert_eq!(nxdomain_proof(&[], &refs(&chain), &name("a.example.")), None);
+        // Every record is needed for some proof, dropping any of them leaves one of the three names unproven.
+        for (owner, _) in &chain {
+            let partial = without(&chain, &owner.to_string());
+            let names = ["b.example.", "example.", "*.example."].map(name);
+            let proven = nxdomain_proof(&[], &refs(&partial), &names[0]);
+            let needed = names.iter().any(|name| {
+                partial
+                    .iter()
+                    .all(|(owner, data)| !nsec3_matches(owner, data, name) && !nsec3_covers(owner, data, name))
+            });
+            assert_eq!(proven.is_none(), needed, "without {owner}");
+        }
+        let opt_out = nsec3_chain("example.", NSEC3_ZONE, true);
+        assert_eq!(nxdomain_proof(&[], &refs(&opt_out), &name("b.example.")), Some(Security::Insecure));
+    }
+
+    #[test]
+    fn nsec3_nodata() {
+        let chain = nsec3_chain("example.", NSEC3_ZONE, false);
+        assert_eq!(
+            nodata_proof(&[], &refs(&chain), &name("a.example."), RecordType::TXT),
+            Some(Security::Secure)
+        );
+        assert_eq!(nodata_proof(&[], &refs(&chain), &name("a.example."), RecordType::A), None);
+        assert_eq!(
+            nodata_proof(&[], &refs(&chain), &name("y.example."), RecordType::A),
+            Some(Security::Secure)
+        );
+        assert_eq!(
+            nodata_proof(&[], &refs(&chain), &name("z.w.example."), RecordType::MX),
+            Some(Security::Secure)
+        );
+        assert_eq!(nodata_proof(&[], &refs(&chain), &name("z.w.example."), RecordType::A), None);
+        // An unsigned delegation, under an opt-out record.
+        let opt_out = nsec3_chain("example.", NSEC3_ZONE, true);
+        assert_eq!(
+            nodata_proof(&[], &refs(&opt_out), &name("unsigned.example."), RecordType::DS),
+            Some(Security::Insecure)
+        );
+        assert_eq!(nodata_proof(&[], &refs(&chain), &name("unsigned.example."), RecordType::DS), None);
+    }
+
+    #[test]
+    fn nsec3_wildcard() {
+        let chain = nsec3_chain("example.", NSEC3_ZONE, false);
+        assert_eq!(wildcard_proof(&[], &refs(&chain), &name("q.w.example."), 2), Some(Security::Secure));
+        // Fewer labels than the wildcard has: the next closer name is then w.example., which exists.
+        assert_eq!(wildcard_proof(&[], &refs(&chain), &name("q.w.example."), 1), None);
+        let opt_out = nsec3_chain("example.", NSEC3_ZONE, true);
+        assert_eq!(
+            wildcard_proof(&[], &refs(&opt_out), &name("q.w.example."), 2),
+            Some(Security::Insecure)
+        );
+    }
+
+    /// A signed zone of the test hierarchy.
+    struct Zone {
+        name: Name,
+        key: EcdsaSigningKey,
+        dnskey: DNSKEY,
+    }
+
+    impl Zone {
+        fn new(zone: &str) -> Self {
+            let pkcs8 = EcdsaSigningKey::generate_pkcs8(Algorithm::ECDSAP256SHA256).unwrap();
+            let key = EcdsaSigningKey::from_pkcs8(&pkcs8, Algorithm::ECDSAP256SHA256).unwrap();
+            let dnskey = DNSKEY::with_flags(257, key.to_public_key().unwrap());
+            Zone {
+                name: name(zone),
+                key,
+                dnskey,
+            }
+        }
+
+        /// A signature of an RRset, valid from `inception` to `expiration` days from now.
+        fn signature(&self, records: &[Record], inception: i64, expiration: i64) -> Record {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+            let record = &records[0];
+            let input = SigInput {
+                type_covered: record.record_type(),
+                algorithm: Algorithm::ECDSAP256SHA256,
+                num_labels: record.name.num_labels(),
+                original_ttl: record.ttl,
+                sig_expiration: SerialNumber::new((now + expiration * 86400) as u32),
+                sig_inception: SerialNumber::new((now + inception * 86400) as u32),
+                key_tag: self.dnskey.calculate_key_tag().unwrap(),
+                signer_name: self.name.clone(),
+            };
+            let tbs = TBS::from_input(&record.name, DNSClass::IN, &input, records.iter()).unwrap();
+            let sig = RRSIG::from_sig(input, self.key.sign(&tbs).unwrap());
+            Record::from_rdata(record.name.clone(), record.ttl, RData::DNSSEC(DNSSECRData::RRSIG(sig)))
+        }
+
+        fn signed(&self, records: Vec<Record>) -> Vec<Record> {
+            let sig = self.signature(&records, -1, 30);
+            records.into_iter().chain([sig]).collect()
+        }
+
+        fn soa(&self) -> Record {
+            let soa = SOA::new(name("ns.test."), name("root.test."), 1, 3600, 600, 86400, 300);
+            Record::from_rdata(self.name.clone(), 300, RData::SOA(soa))
+        }
+
+        fn ds(&self) -> Record {
+            let digest = self.dnskey.to_digest(&self.name, DigestType::SHA256).unwrap();
+            let key_tag = self.dnskey.calculate_key_tag().unwrap();
+            let ds = DS::new(key_tag, Algorithm::ECDSAP256SHA256, DigestType::SHA256, digest.as_ref().to_vec());
+            Record::from_rdata(self.name.clone(), 3600, RData::DNSSEC(DNSSECRData::DS(ds)))
+        }
+
+        fn dnskeys(&self) -> Vec<Record> {
+            let dnskey = RData::DNSSEC(DNSSECRData::DNSKEY(self.dnskey.clone()));
+            self.signed(vec![Record::from_rdata(self.name.clone(), 3600, dnskey)])
+        }
+    }
+
+    fn a(owner: &str, ip: &str) -> Record {
+        Record::from_rdata(name(owner), 300, RData::A(A(ip.parse().unwrap())))
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[RecordType]) -> Record {
+        let nsec = NSEC::new_cover_self(name(next), types.iter().copied());
+        Record::from_rdata(name(owner), 300, RData::DNSSEC(DNSSECRData::NSEC(nsec)))
+    }
+
+    /// The upstream of the tests, answering from fixed responses and with NXDOMAIN to anything else.
+    #[derive(Default)]
+    struct Zones(HashMap<(Name, RecordType), Message>);
+
+    impl Zones {
+        fn add(
+            &mut self,
+            owner: &str,
+            record_type: RecordType,
+            response_code: ResponseCode,
+            answers: Vec<Record>,
+            authorities: Vec<Record>,
+        ) {
+            let mut response = Message::new(0, MessageType::Response, OpCode::Query);
+            response.metadata.response_code = response_code;
+            response.add_query(Query::query(name(owner), record_type));
+            response.add_answers(answers);
+            response.add_authorities(authorities);
+            self.0.insert((name(owner), record_type), response);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Upstream for Zones {
+        async fn exchange(&self, query: Message) -> Result<Message> {
+            let question = query.queries.first().ok_or("no question")?;
+            let mut response = match self.0.get(&(question.name().clone(), question.query_type())) {
+                Some(response) => response.clone(),
+                None => dns::build_response(&query, ResponseCode::NXDomain),
+            };
+            response.metadata.id = query.metadata.id;
+            Ok(response)
+        }
+    }
+
+    /// The root and `test.` above the signed zone `sec.test.`, with an unsigned delegation `insecure.test.` and one
+    /// `forged.test.` the lack of DS records of which is not proven. `unsigned.sec.test.` is proven to be part of
+    /// `sec.test.`.
+    fn hierarchy(sec: &Zone) -> (Zone, Zones) {
+        use {RecordType::*, ResponseCode::*};
+        let (root, test) = (Zone::new("."), Zone::new("test."));
+        let mut zones = Zones::default();
+        zones.add(".", DNSKEY, NoError, root.dnskeys(), vec![]);
+        zones.add("test.", DS, NoError, root.signed(vec![test.ds()]), vec![]);
+        zones.add("test.", DNSKEY, NoError, test.dnskeys(), vec![]);
+        zones.add("sec.test.", DS, NoError, test.signed(vec![sec.ds()]), vec![]);
+        zones.add("sec.test.", DNSKEY, NoError, sec.dnskeys(), vec![]);
+        let no_ds = test.signed(vec![nsec("insecure.test.", "sec.test.", &[NS])]);
+        zones.add(
+            "insecure.test.",
+            DS,
+            NoError,
+            vec![],
+            [test.signed(vec![test.soa()]), no_ds].concat(),
+        );
+        zones.add("forged.test.", DS, NoError, vec![], test.signed(vec![test.soa()]));
+        let not_delegated = sec.signed(vec![nsec("unsigned.sec.test.", "www.sec.test.", &[A])]);
+        zones.add("unsigned.sec.test.", DS, NoError, vec![], not_delegated);
+        (root, zones)
+    }
+
+    async fn resolve(root: &Zone, zones: Zones, owner: &str, record_type: RecordType) -> Message {
+        let anchor = std::env::temp_dir().join(format!("dns2socks-anchor-{}-{}.txt", std::process::id(), rand::random::<u32>()));
+        let dnskey = Record::from_rdata(Name::root(), 3600, RData::DNSSEC(DNSSECRData::DNSKEY(root.dnskey.clone())));
+        std::fs::write(&anchor, format!("{dnskey}\n")).unwrap();
+        let mut config = Config::default();
+        config.dnssec(true).dnssec_trust_anchor(Some(anchor.clone()));
+        let resolver = Resolver::with_upstream(config, Arc::new(zones));
+        std::fs::remove_file(&anchor).unwrap();
+
+        let mut query = Message::new(1, MessageType::Query, OpCode::Query);
+        query.metadata.recursion_desired = true;
+        query.metadata.authentic_data = true;
+        query.add_query(Query::query(name(owner), record_type));
+        query.set_edns(Edns::new());
+        resolver.unwrap().query(query).await.unwrap()
+    }
+
+    /// The Extended DNS Error info code of a response.
+    fn ede(response: &Message) -> Option<u16> {
+        let options = response.edns.as_ref()?.options();
+        options.as_ref().iter().find_map(|(_, option)| match option {
+            EdnsOption::Unknown(EDE_OPTION, info) => Some(u16::from_be_bytes([info[0], info[1]])),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn chain_of_trust() {
+        use {RecordType::*, ResponseCode::*};
+        let sec = Zone::new("sec.test.");
+        let (root, mut zones) = hierarchy(&sec);
+        zones.add(
+            "www.sec.test.",
+            A,
+            NoError,
+            sec.signed(vec![a("www.sec.test.", "192.0.2.1")]),
+            vec![],
+        );
+        zones.add("www.insecure.test.", A, NoError, vec![a("www.insecure.test.", "192.0.2.2")], vec![]);
+        let response = resolve(&root, zones, "www.sec.test.", A).await;
+        assert_eq!(response.metadata.response_code, NoError);
+        assert!(response.metadata.authentic_data);
+        assert_eq!(response.answers.len(), 1);
+
+        let (root, mut zones) = hierarchy(&sec);
+        zones.add("www.insecure.test.", A, NoError, vec![a("www.insecure.test.", "192.0.2.2")], vec![]);
+        let response = resolve(&root, zones, "www.insecure.test.", A).await;
+        assert_eq!(response.metadata.response_code, NoError);
+        assert!(!response.metadata.authentic_data);
+    }
+
+    #[tokio::test]
+    async fn chain_of_trust_failures() {
+        use {RecordType::*, ResponseCode::*};
+        let sec = Zone::new("sec.test.");
+        let forged_sig = sec.signature(&[a("bad.sec.test.", "192.0.2.1")], -1, 30);
+        let expired = vec![a("old.sec.test.", "192.0.2.1")];
+        let expired_sig = sec.signature(&expired, -10, -1);
+        let cases: [(&str, Vec<Record>, Ede); 4] = [
+            (
+                "bad.sec.test.",
+                vec![a("bad.sec.test.", "192.0.2.66"), forged_sig],
+                Ede::DnssecBogus,
+            ),
+            ("old.sec.test.", [expired, vec![expired_sig]].concat(), Ede::SignatureExpired),
+            // Unsigned in a signed zone.
+            ("unsigned.sec.test.", vec![a("unsigned.sec.test.", "192.0.2.1")], Ede::RrsigsMissing),
+            // Claimed to be in an unsigned zone, without proof.
+            ("www.forged.test.", vec![a("www.forged.test.", "192.0.2.1")], Ede::NsecMissing),
+        ];
+        for (owner, answers, expected) in cases {
+            let (root, mut zones) = hierarchy(&sec);
+            zones.add(owner, A, NoError, answers, vec![]);
+            let response = resolve(&root, zones, owner, A).await;
+            assert_eq!(response.metadata.response_code, ServFail, "{owner}");
+            assert_eq!(ede(&response), Some(expected as u16), "{owner}");
+        }
+
+        // A different key for the zone than its DS records name.
+        let other = Zone::new("sec.test.");
+        let (root, mut zones) = hierarchy(&sec);
+        zones.add("sec.test.", DNSKEY, NoError, other.dnskeys(), vec![]);
+        zones.add(
+            "www.sec.test.",
+            A,
+            NoError,
+            other.signed(vec![a("www.sec.test.", "192.0.2.1")]),
+            vec![],
+        );
+        let response = resolve(&root, zones, "www.sec.test.", A).await;
+        assert_eq!(ede(&response), Some(Ede::DnskeyMissing as u16));
+    }
+
+    #[tokio::test]
+    async fn denial_through_the_chain() {
+        use {RecordType::*, ResponseCode::*};
+        let sec = Zone::new("sec.test.");
+        let proof = sec.signed(vec![nsec("sec.test.", "www.sec.test.", &[SOA, NS, DNSKEY])]);
+        let (root, mut zones) = hierarchy(&sec);
+        zones.add("nx.sec.test.", A, NXDomain, vec![], [sec.signed(vec![sec.soa()]), proof].concat());
+        let response = resolve(&root, zones, "nx.sec.test.", A).await;
+        assert_eq!(response.metadata.response_code, NXDomain);
+        assert!(response.metadata.authentic_data);
+
+        // A signed SOA replayed from another answer proves nothing.
+        let (root, mut zones) = hierarchy(&sec);
+        zones.add("nx.sec.test.", A, NXDomain, vec![], sec.signed(vec![sec.soa()]));
+        let response = resolve(&root, zones, "nx.sec.test.", A).await;
+        assert_eq!(response.metadata.response_code, ServFail);
+        assert_eq!(ede(&response), Some(Ede::NsecMissing as u16));
+    }
+
+    #[tokio::test]
+    async fn unvalidated_sections_left_out() {
+        use {RecordType::*, ResponseCode::*};
+        let sec = Zone::new("sec.test.");
+        let (root, mut zones) = hierarchy(&sec);
+        let forged_ns = Record::from_rdata(
+            name("sec.test."),
+            300,
+            RData::NS(hickory_proto::rr::rdata::NS(name("ns.evil.example."))),
+        );
+        let answers = sec.signed(vec![a("www.sec.test.", "192.0.2.1")]);
+        zones.add(
+            "www.sec.test.",
+            A,
+            NoError,
+            answers,
+            [vec![forged_ns], sec.signed(vec![sec.soa()])].concat(),
+        );
+        let response = resolve(&root, zones, "www.sec.test.", A).await;
+        assert!(response.metadata.authentic_data);
+        assert_eq!(
+            response.authorities.iter().map(|record| record.record_type()).collect::<Vec<_>>(),
+            [SOA]
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! dnstap (https://dnstap.info) capture of the client and forwarded queries and responses, in Frame Streams to a
+//! Unix socket or a file. The few protobuf fields of the dnstap schema are encoded by hand.
+
+use crate::config::Config;
+use hickory_proto::op::Message;
+use socks5_impl::Result;
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Frames waiting to be written, more are dropped rather than slowing down the queries.
+const QUEUE_SIZE: usize = 4096;
+
+/// How long to wait before connecting again to a socket that is not there or went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Frame Streams control frame types.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// `Dnstap.Type.MESSAGE`
+const DNSTAP_MESSAGE: u64 = 1;
+
+/// `Message.Type`
+#[derive(Debug, Clone, Copy)]
+enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// `SocketFamily` and `SocketProtocol`
+const FAMILY_INET: u64 = 1;
+const FAMILY_INET6: u64 = 2;
+const PROTOCOL_UDP: u64 = 1;
+const PROTOCOL_TCP: u64 = 2;
+
+/// One dnstap `Message`, the addresses are the ones of the side that sent the query and the side that answers it.
+struct Event<'a> {
+    message_type: MessageType,
+    use_tcp: bool,
+    query_address: Option<SocketAddr>,
+    response_address: Option<SocketAddr>,
+    query_time: SystemTime,
+    query: Option<&'a [u8]>,
+    response: Option<(SystemTime, &'a [u8])>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Dnstap {
+    frames: SyncSender<Vec<u8>>,
+}
+
+impl Dnstap {
+    pub(crate) fn new(config: &Config) -> Result<Option<Self>> {
+        let output = match (&config.dnstap_socket, &config.dnstap_file) {
+            (Some(path), _) => Output::Socket(path.clone()),
+            (None, Some(path)) => {
+                let file = std::fs::File::create(path).map_err(|e| format!("dnstap file {} error \"{}\"", path.display(), e))?;
+                Output::File(std::io::BufWriter::new(file))
+            }
+            (None, None) => return Ok(None),
+        };
+        let (frames, receiver) = std::sync::mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("dnstap".to_owned())
+            .spawn(move || output.run(receiver))?;
+        Ok(Some(Dnstap { frames }))
+    }
+
+    /// A query received from `client`, or from a Unix socket client when `None`.
+    pub(crate) fn client_query(&self, client: Option<SocketAddr>, use_tcp: bool, received: SystemTime, query: &[u8]) {
+        self.send(Event {
+            message_type: MessageType::ClientQuery,
+            use_tcp,
+            query_address: client,
+            response_address: None,
+            query_time: received,
+            query: Some(query),
+            response: None,
+        });
+    }
+
+    pub(crate) fn client_response(
+        &self,
+        client: Option<SocketAddr>,
+        use_tcp: bool,
+        received: SystemTime,
+        query: &[u8],
+        response: &Message,
+    ) {
+        let Ok(response) = response.to_vec() else {
+            return;
+        };
+        self.send(Event {
+            message_type: MessageType::ClientResponse,
+            use_tcp,
+            query_address: client,
+            response_address: None,
+            query_time: received,
+            query: Some(query),
+            response: Some((SystemTime::now(), &response)),
+        });
+    }
+
+    /// A query sent through the proxy to `server`, `None` when the proxy resolves the server name.
+    pub(crate) fn forwarder_query(&self, server: Option<SocketAddr>, use_tcp: bool, sent: SystemTime, query: &[u8]) {
+        self.send(Event {
+            message_type: MessageType::ForwarderQuery,
+            use_tcp,
+            query_address: None,
+            response_address: server,
+            query_time: sent,
+            query: Some(query),
+            response: None,
+        });
+    }
+
+    pub(crate) fn forwarder_response(&self, server: Option<SocketAddr>, use_tcp: bool, sent: SystemTime, query: &[u8], response: &[u8]) {
+        self.send(Event {
+            message_type: MessageType::ForwarderResponse,
+            use_tcp,
+            query_address: None,
+            response_address: server,
+            query_time: sent,
+            query: Some(query),
+            response: Some((SystemTime::now(), response)),
+        });
+    }
+
+    fn send(&self, event: Event) {
+        if let Err(TrySendError::Full(_)) = self.frames.try_send(encode(&event)) {
+            log::debug!("dnstap queue full, dropping a message");
+        }
+    }
+}
+
+/// The `Dnstap` protobuf of an event.
+fn encode(event: &Event) -> Vec<u8> {
+    let mut message = Protobuf::default();
+    message.varint(1, event.message_type as u64);
+    let family_address = event.query_address.or(event.response_address);
+    if let Some(addr) = family_address {
+        message.varint(
+            2,
+            if addr.ip().to_canonical().is_ipv4() {
+                FAMILY_INET
+            } else {
+                FAMILY_INET6
+            },
+        );
+    }
+    message.varint(3, if event.use_tcp { PROTOCOL_TCP } else { PROTOCOL_UDP });
+    if let Some(addr) = event.query_address {
+        message.bytes(4, &ip_bytes(addr.ip()));
+    }
+    if let Some(addr) = event.response_address {
+        message.bytes(5, &ip_bytes(addr.ip()));
+    }
+    if let Some(addr) = event.query_address {
+        message.varint(6, u64::from(addr.port()));
+    }
+    if let Some(addr) = event.response_address {
+        message.varint(7, u64::from(addr.port()));
+    }
+    let (seconds, nanos) = unix_time(event.query_time);
+    message.varint(8, seconds);
+    message.fixed32(9, nanos);
+    if let Some(query) = event.query {
+        message.bytes(10, query);
+    }
+    if let Some((time, response)) = event.response {
+        let (seconds, nanos) = unix_time(time);
+        message.varint(12, seconds);
+        message.fixed32(13, nanos);
+        message.bytes(14, response);
+    }
+
+    let mut dnstap = Protobuf::default();
+    dnstap.bytes(2, concat!("dns2socks ", env!("CARGO_PKG_VERSION")).as_bytes());
+    dnstap.bytes(14, &message.0);
+    dnstap.varint(15, DNSTAP_MESSAGE);
+    dnstap.0
+}
+
+fn ip_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr.to_canonical() {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+#[derive(Default)]
+struct Protobuf(Vec<u8>);
+
+impl Protobuf {
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.raw_varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn varint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.raw_varint(value);
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.raw_varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+}
+
+/// A Frame Streams control frame, with the dnstap content type unless it is a FINISH or a STOP.
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut payload = control_type.to_be_bytes().to_vec();
+    if control_type != CONTROL_STOP && control_type != CONTROL_FINISH {
+        payload.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend_from_slice(CONTENT_TYPE);
+    }
+    // An escape, a zero data frame length, comes before every control frame.
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn data_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    frame
+}
+
+enum Output {
+    /// A bidirectional stream, the reader accepts the content type before any data.
+    Socket(PathBuf),
+    /// A unidirectional stream.
+    File(std::io::BufWriter<std::fs::File>),
+}
+
+impl Output {
+    /// Write the frames as they come, until every sender is gone.
+    fn run(self, frames: Receiver<Vec<u8>>) {
+        match self {
+            Output::File(mut file) => {
+                let result = write_frames(&mut file, &frames);
+                if let Err(e) = result.and_then(|_| file.flush()) {
+                    log::warn!("dnstap file error \"{}\"", e);
+                }
+            }
+            #[cfg(unix)]
+            Output::Socket(path) => run_socket(&path, &frames),
+            #[cfg(not(unix))]
+            Output::Socket(path) => log::warn!("dnstap socket {} is not supported on this platform", path.display()),
+        }
+    }
+}
+
+/// Start the stream, write the frames until every sender is gone and stop it.
+fn write_frames<W: Write>(stream: &mut W, frames: &Receiver<Vec<u8>>) -> std::io::Result<()> {
+    stream.write_all(&control_frame(CONTROL_START))?;
+    stream.flush()?;
+    while let Ok(frame) = frames.recv() {
+        stream.write_all(&data_frame(&frame))?;
+        while let Ok(frame) = frames.try_recv() {
+            stream.write_all(&data_frame(&frame))?;
+        }
+        stream.flush()?;
+    }
+    stream.write_all(&control_frame(CONTROL_STOP))?;
+    stream.flush()
+}
+
+/// Stream to the socket, connecting again whenever it is not there, and dropping the frames in the meantime. Only the
+/// first of a run of failed attempts is a warning.
+#[cfg(unix)]
+fn run_socket(path: &Path, frames: &Receiver<Vec<u8>>) {
+    let mut connected = true;
+    loop {
+        let result = std::os::unix::net::UnixStream::connect(path).and_then(|mut stream| {
+            handshake(&mut stream)?;
+            connected = true;
+            log::info!("dnstap connected to {}", path.display());
+            write_frames(&mut stream, frames)?;
+            // Every sender is gone, wait for the reader to acknowledge the end of the stream.
+            read_control_frame(&mut stream).map(|_| ())
+        });
+        match result {
+            Ok(()) => return,
+            Err(e) if connected => log::warn!("dnstap socket {} error \"{}\"", path.display(), e),
+            Err(e) => log::debug!("dnstap socket {} error \"{}\"", path.display(), e),
+        }
+        connected = false;
+        let retry = Instant::now() + RECONNECT_INTERVAL;
+        loop {
+            match frames.recv_timeout(retry.saturating_duration_since(Instant::now())) {
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn handshake<S: Read + Write>(stream: &mut S) -> std::io::Result<()> {
+    stream.write_all(&control_frame(CONTROL_READY))?;
+    stream.flush()?;
+    match read_control_frame(stream)? {
+        CONTROL_ACCEPT => Ok(()),
+        other => Err(std::io::Error::other(format!("unexpected control frame {other} instead of ACCEPT"))),
+    }
+}
+
+/// Read a control frame and return its type.
+fn read_control_frame<R: Read>(stream: &mut R) -> std::io::Result<u32> {
+    let mut word = [0u8; 4];
+    stream.read_exact(&mut word)?;
+    if u32::from_be_bytes(word) != 0 {
+        return Err(std::io::Error::other("data frame instead of a control frame"));
+    }
+    stream.read_exact(&mut word)?;
+    let len = u32::from_be_bytes(word) as usize;
+    if !(4..=512).contains(&len) {
+        return Err(std::io::Error::other("invalid control frame length"));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]))
+}
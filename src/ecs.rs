@@ -0,0 +1,87 @@
+//! EDNS Client Subnet (RFC 7871) handling of the queries forwarded through the proxy, and the subnet their
+//! answers are cached for.
+
+use crate::config::{Config, EcsMode};
+use hickory_proto::{
+    op::{Edns, Message},
+    rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption},
+};
+use ipnet::IpNet;
+use std::borrow::Cow;
+
+#[derive(Debug)]
+pub(crate) struct Ecs {
+    mode: EcsMode,
+    subnet: Option<IpNet>,
+}
+
+impl Ecs {
+    pub(crate) fn new(config: &Config) -> Self {
+        Ecs {
+            mode: config.ecs,
+            subnet: config.ecs_subnet.map(|subnet| subnet.trunc()),
+        }
+    }
+
+    /// The query to forward, with the option of the client removed or replaced.
+    pub(crate) fn on_query<'a>(&self, query: &'a Message) -> Cow<'a, Message> {
+        let replacement = match (self.mode, self.subnet) {
+            (EcsMode::Pass, _) => return Cow::Borrowed(query),
+            (EcsMode::Replace, Some(subnet)) => Some(ClientSubnet::new(subnet.network(), subnet.prefix_len(), 0)),
+            _ => None,
+        };
+        if replacement.is_none() && client_subnet(query).is_none() {
+            return Cow::Borrowed(query);
+        }
+        let mut query = query.clone();
+        let edns = query.edns.get_or_insert_with(Edns::new);
+        edns.options_mut().remove(EdnsCode::Subnet);
+        if let Some(replacement) = replacement {
+            edns.options_mut().insert(EdnsOption::Subnet(replacement));
+        }
+        Cow::Owned(query)
+    }
+
+    /// Answer the client with the option it sent, if any. The scope of the upstream only applies to the client
+    /// subnet when the option was passed on unchanged.
+    pub(crate) fn on_response(&self, query: &Message, mut response: Message) -> Message {
+        if self.mode == EcsMode::Pass {
+            return response;
+        }
+        if query.edns.is_none() {
+            response.edns = None;
+            return response;
+        }
+        if let Some(edns) = &mut response.edns {
+            edns.options_mut().remove(EdnsCode::Subnet);
+            if let Some(mut subnet) = client_subnet(query) {
+                subnet.set_scope_prefix(0);
+                edns.options_mut().insert(EdnsOption::Subnet(subnet));
+            }
+        }
+        response
+    }
+}
+
+/// The client subnet option of a message.
+fn client_subnet(message: &Message) -> Option<ClientSubnet> {
+    match message.edns.as_ref()?.option(EdnsCode::Subnet)? {
+        EdnsOption::Subnet(subnet) => Some(*subnet),
+        _ => None,
+    }
+}
+
+/// The subnet a forwarded query was sent for, the cache key of answers that depend on it.
+pub(crate) fn query_subnet(query: &Message) -> Option<IpNet> {
+    let subnet = client_subnet(query)?;
+    IpNet::new(subnet.addr(), subnet.source_prefix()).ok().map(|subnet| subnet.trunc())
+}
+
+/// The subnet the answer to a forwarded query is cached for, `None` when the upstream says it is valid for
+/// every client.
+pub(crate) fn cache_scope(query: &Message, response: &Message) -> Option<IpNet> {
+    match client_subnet(response)?.scope_prefix() {
+        0 => None,
+        _ => query_subnet(query),
+    }
+}
@@ -0,0 +1,354 @@
+//! Fake-IP mode: A/AAAA queries are answered with addresses from a private pool, and the pool keeps the mapping
+//! back to the queried domain so a TUN front end such as tun2proxy can turn connections to those addresses into
+//! domain based SOCKS5 CONNECTs.
+
+use crate::{
+    config::{Config, parse_fqdn},
+    dns,
+};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{A, AAAA, PTR},
+    },
+};
+use ipnet::IpNet;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+/// TTL of the fake answers, kept short so clients come back and keep their mapping recently used.
+const FAKE_IP_TTL: u32 = 60;
+
+/// How often a changed mapping is written to the persistence file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The pool of the running instance, looked up by [`fake_ip_lookup`] and the C API.
+static ACTIVE: RwLock<Option<Arc<FakeIp>>> = RwLock::new(None);
+
+/// Addresses of one family handed out in order, then recycled least recently used first once all are taken.
+#[derive(Debug)]
+struct Pool {
+    net: IpNet,
+    /// Number of usable addresses, the network address (and the IPv4 broadcast address) are left out.
+    size: u128,
+    next: u128,
+    tick: u64,
+    by_name: HashMap<Name, u128>,
+    by_offset: HashMap<u128, (Name, u64)>,
+    lru: BTreeMap<u64, u128>,
+}
+
+impl Pool {
+    fn new(net: IpNet) -> Option<Self> {
+        let host_bits = u32::from(net.max_prefix_len() - net.prefix_len());
+        let mut size = 1u128.checked_shl(host_bits).map_or(u128::MAX, |n| n - 1);
+        if matches!(net, IpNet::V4(_)) && host_bits > 1 {
+            size -= 1;
+        }
+        (size > 0).then(|| Pool {
+            net,
+            size,
+            next: 0,
+            tick: 0,
+            by_name: HashMap::new(),
+            by_offset: HashMap::new(),
+            lru: BTreeMap::new(),
+        })
+    }
+
+    fn addr(&self, offset: u128) -> IpAddr {
+        match self.net {
+            IpNet::V4(net) => Ipv4Addr::from(u32::from(net.network()) + 1 + offset as u32).into(),
+            IpNet::V6(net) => Ipv6Addr::from(u128::from(net.network()) + 1 + offset).into(),
+        }
+    }
+
+    fn offset(&self, addr: IpAddr) -> Option<u128> {
+        let offset = match (self.net, addr) {
+            (IpNet::V4(net), IpAddr::V4(addr)) => u128::from(u32::from(addr).checked_sub(u32::from(net.network()))?),
+            (IpNet::V6(net), IpAddr::V6(addr)) => u128::from(addr).checked_sub(u128::from(net.network()))?,
+            _ => return None,
+        };
+        offset.checked_sub(1).filter(|offset| *offset < self.size)
+    }
+
+    fn touch(&mut self, offset: u128) {
+        self.tick += 1;
+        if let Some((_, last_used)) = self.by_offset.get_mut(&offset) {
+            self.lru.remove(last_used);
+            *last_used = self.tick;
+            self.lru.insert(self.tick, offset);
+        }
+    }
+
+    /// The address of `name`, allocating one if it has none yet.
+    fn assign(&mut self, name: &Name) -> IpAddr {
+        if let Some(&offset) = self.by_name.get(name) {
+            self.touch(offset);
+            return self.addr(offset);
+        }
+        let offset = if self.next < self.size {
+            self.next += 1;
+            self.next - 1
+        } else {
+            // Every address is taken, the pool is not empty so there is a least recently used one.
+            let (_, offset) = self.lru.pop_first().unwrap_or_default();
+            if let Some((old, _)) = self.by_offset.remove(&offset) {
+                log::debug!("Fake IP {} recycled from {} to {}", self.addr(offset), old, name);
+                self.by_name.remove(&old);
+            }
+            offset
+        };
+        self.insert(offset, name.clone());
+        self.addr(offset)
+    }
+
+    fn insert(&mut self, offset: u128, name: Name) {
+        self.tick += 1;
+        if let Some((old, last_used)) = self.by_offset.insert(offset, (name.clone(), self.tick)) {
+            self.by_name.remove(&old);
+            self.lru.remove(&last_used);
+        }
+        if let Some(old) = self.by_name.insert(name, offset)
+            && old != offset
+            && let Some((_, last_used)) = self.by_offset.remove(&old)
+        {
+            self.lru.remove(&last_used);
+        }
+        self.lru.insert(self.tick, offset);
+        self.next = self.next.max(offset + 1);
+    }
+
+    fn lookup(&mut self, addr: IpAddr) -> Option<Name> {
+        let offset = self.offset(addr)?;
+        let name = self.by_offset.get(&offset)?.0.clone();
+        self.touch(offset);
+        Some(name)
+    }
+
+    /// The mappings, least recently used first.
+    fn entries(&self) -> impl Iterator<Item = (IpAddr, &Name)> {
+        self.lru
+            .values()
+            .filter_map(|offset| Some((self.addr(*offset), &self.by_offset.get(offset)?.0)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pools {
+    v4: Option<Pool>,
+    v6: Option<Pool>,
+    dirty: bool,
+}
+
+impl Pools {
+    fn pool_mut(&mut self, addr: IpAddr) -> Option<&mut Pool> {
+        match addr {
+            IpAddr::V4(_) => self.v4.as_mut(),
+            IpAddr::V6(_) => self.v6.as_mut(),
+        }
+    }
+}
+
+/// The fake address pools and their mapping back to domains.
+#[derive(Debug)]
+pub(crate) struct FakeIp {
+    pools: Mutex<Pools>,
+    file: Option<PathBuf>,
+}
+
+impl FakeIp {
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        let mut pools = Pools::default();
+        for net in &config.fake_ip {
+            let Some(pool) = Pool::new(*net) else {
+                log::warn!("Fake IP pool {} has no usable addresses", net);
+                continue;
+            };
+            let slot = match net {
+                IpNet::V4(_) => &mut pools.v4,
+                IpNet::V6(_) => &mut pools.v6,
+            };
+            if slot.is_some() {
+                log::warn!("Ignoring fake IP pool {}, only one pool per address family is used", net);
+                continue;
+            }
+            *slot = Some(pool);
+        }
+        if pools.v4.is_none() && pools.v6.is_none() {
+            return None;
+        }
+        let fake_ip = FakeIp {
+            pools: Mutex::new(pools),
+            file: config.fake_ip_file.clone(),
+        };
+        fake_ip.load();
+        Some(fake_ip)
+    }
+
+    /// Restore the mappings saved by a previous run, one `address domain` pair per line.
+    fn load(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                log::warn!("Fake IP file {} error \"{}\"", path.display(), e);
+                return;
+            }
+        };
+        let Ok(mut pools) = self.pools.lock() else {
+            return;
+        };
+        let mut restored = 0;
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(Ok(addr)), Some(Ok(name))) = (fields.next().map(str::parse::<IpAddr>), fields.next().map(parse_fqdn)) else {
+                continue;
+            };
+            // Entries outside the configured pools are left over from a different configuration.
+            if let Some(pool) = pools.pool_mut(addr)
+                && let Some(offset) = pool.offset(addr)
+            {
+                pool.insert(offset, name);
+                restored += 1;
+            }
+        }
+        log::info!("Restored {} fake IP mappings from {}", restored, path.display());
+    }
+
+    /// Write the mappings to the persistence file if they changed since the last save.
+    pub(crate) fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let content = {
+            let Ok(mut pools) = self.pools.lock() else {
+                return;
+            };
+            if !pools.dirty {
+                return;
+            }
+            pools.dirty = false;
+            let entries = pools.v4.iter().chain(&pools.v6).flat_map(Pool::entries);
+            entries.map(|(addr, name)| format!("{addr} {name}\n")).collect::<String>()
+        };
+        // Write to a temporary file first so a crash never leaves a half written map behind.
+        let tmp = path.with_extension("tmp");
+        if let Err(e) = std::fs::write(&tmp, content).and_then(|_| std::fs::rename(&tmp, path)) {
+            log::warn!("Fake IP file {} error \"{}\"", path.display(), e);
+        }
+    }
+
+    /// Periodically save the mappings until shutdown.
+    pub(crate) async fn persist(self: Arc<Self>, shutdown_token: tokio_util::sync::CancellationToken) -> socks5_impl::Result<()> {
+        if self.file.is_none() {
+            shutdown_token.cancelled().await;
+            return Ok(());
+        }
+        loop {
+            tokio::select! {
+                _ = shutdown_token.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(SAVE_INTERVAL) => {}
+            }
+            let fake_ip = self.clone();
+            tokio::task::spawn_blocking(move || fake_ip.save())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    /// Answer A and AAAA queries with fake addresses, and PTR queries for them with their domain.
+    /// Returns `None` for any other query, which is resolved normally.
+    pub(crate) fn resolve(&self, query: &Message) -> Option<Message> {
+        let question = query.queries.first()?;
+        let name = question.name();
+        let mut pools = self.pools.lock().ok()?;
+        let rdata = match question.query_type() {
+            RecordType::A => pools.v4.as_mut().map(|pool| pool.assign(name)),
+            RecordType::AAAA => pools.v6.as_mut().map(|pool| pool.assign(name)),
+            RecordType::PTR => {
+                let addr = name.parse_arpa_name().ok()?.addr();
+                // Addresses outside the fake range are real ones, their reverse lookups are forwarded.
+                let pool = pools.pool_mut(addr).filter(|pool| pool.offset(addr).is_some())?;
+                let domain = pool.lookup(addr);
+                let mut response = dns::build_response(query, ResponseCode::NoError);
+                if let Some(domain) = domain {
+                    response.add_answer(Record::from_rdata(name.clone(), FAKE_IP_TTL, RData::PTR(PTR(domain))));
+                } else {
+                    response.metadata.response_code = ResponseCode::NXDomain;
+                }
+                return Some(response);
+            }
+            _ => return None,
+        };
+        pools.dirty |= rdata.is_some();
+        drop(pools);
+
+        // A family without a pool gets an empty answer, so dual stack clients fall back to the other one.
+        let mut response = dns::build_response(query, ResponseCode::NoError);
+        let rdata = match rdata {
+            Some(IpAddr::V4(addr)) => Some(RData::A(A(addr))),
+            Some(IpAddr::V6(addr)) => Some(RData::AAAA(AAAA(addr))),
+            None => None,
+        };
+        if let Some(rdata) = rdata {
+            response.add_answer(Record::from_rdata(name.clone(), FAKE_IP_TTL, rdata));
+        }
+        Some(response)
+    }
+
+    /// The domain a fake address was handed out for.
+    pub(crate) fn lookup(&self, addr: IpAddr) -> Option<String> {
+        let addr = addr.to_canonical();
+        let name = self.pools.lock().ok()?.pool_mut(addr)?.lookup(addr)?;
+        Some(name.to_ascii().trim_end_matches('.').to_owned())
+    }
+
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        self.pools
+            .lock()
+            .is_ok_and(|mut pools| pools.pool_mut(addr).is_some_and(|pool| pool.offset(addr).is_some()))
+    }
+
+    /// Make this the pool answered by [`fake_ip_lookup`] and [`fake_ip_contains`].
+    pub(crate) fn activate(self: &Arc<Self>) {
+        if let Ok(mut lock) = ACTIVE.write() {
+            *lock = Some(self.clone());
+        }
+    }
+
+    /// Stop answering [`fake_ip_lookup`] and [`fake_ip_contains`] from this pool, unless another one took over.
+    pub(crate) fn deactivate(self: &Arc<Self>) {
+        if let Ok(mut lock) = ACTIVE.write()
+            && lock.as_ref().is_some_and(|active| Arc::ptr_eq(active, self))
+        {
+            *lock = None;
+        }
+    }
+}
+
+fn active() -> Option<Arc<FakeIp>> {
+    ACTIVE.read().ok()?.clone()
+}
+
+/// The domain a fake address was handed out for by the running instance, without the trailing dot.
+///
+/// Returns `None` when fake-IP mode is off, or when the address is not currently mapped.
+pub fn fake_ip_lookup(addr: IpAddr) -> Option<String> {
+    active()?.lookup(addr)
+}
+
+/// Whether an address belongs to one of the fake-IP pools of the running instance.
+pub fn fake_ip_contains(addr: IpAddr) -> bool {
+    active().is_some_and(|fake_ip| fake_ip.contains(addr))
+}
@@ -0,0 +1,93 @@
+//! What the last forwarded queries tell of the proxy and the upstreams, and the upstreams marked down through the
+//! admin API.
+
+use socks5_impl::Error;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Status {
+    pub(crate) last_success: Option<SystemTime>,
+    pub(crate) last_failure: Option<SystemTime>,
+    pub(crate) last_error: Option<String>,
+    pub(crate) consecutive_failures: u64,
+}
+
+impl Status {
+    fn succeeded(&mut self) {
+        self.last_success = Some(SystemTime::now());
+        self.consecutive_failures = 0;
+    }
+
+    fn failed(&mut self, e: &Error) {
+        self.last_failure = Some(SystemTime::now());
+        self.last_error = Some(e.to_string());
+        self.consecutive_failures += 1;
+    }
+
+    /// Whether the last attempt succeeded, or there was none yet.
+    pub(crate) fn healthy(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+}
+
+/// Kept across reloads, an upstream marked down stays down until it is marked up.
+#[derive(Debug, Default)]
+pub(crate) struct Health {
+    proxy: Mutex<Status>,
+    upstreams: Mutex<HashMap<String, Status>>,
+    down: Mutex<HashSet<String>>,
+}
+
+impl Health {
+    /// Record the outcome of connecting to the proxy and completing a SOCKS5 command.
+    pub(crate) fn proxy_handshake<T>(&self, result: &Result<T, Error>) {
+        if let Ok(mut status) = self.proxy.lock() {
+            match result {
+                Ok(_) => status.succeeded(),
+                Err(e) => status.failed(e),
+            }
+        }
+    }
+
+    /// Record whether an answer came back from `upstream`.
+    pub(crate) fn upstream_answer<T>(&self, upstream: &str, result: &Result<T, Error>) {
+        if let Ok(mut upstreams) = self.upstreams.lock() {
+            let status = upstreams.entry(upstream.to_owned()).or_default();
+            match result {
+                Ok(_) => status.succeeded(),
+                Err(e) => status.failed(e),
+            }
+        }
+    }
+
+    pub(crate) fn proxy(&self) -> Status {
+        self.proxy.lock().map(|status| status.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn upstream(&self, upstream: &str) -> Status {
+        let upstreams = self.upstreams.lock();
+        upstreams
+            .ok()
+            .and_then(|upstreams| upstreams.get(upstream).cloned())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn is_down(&self, upstream: &str) -> bool {
+        self.down.lock().is_ok_and(|down| down.contains(upstream))
+    }
+
+    /// Mark an upstream down, its queries fail without being sent, or up again.
+    pub(crate) fn set_down(&self, upstream: &str, down: bool) {
+        if let Ok(mut marked) = self.down.lock() {
+            if down {
+                marked.insert(upstream.to_owned());
+            } else {
+                marked.remove(upstream);
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{
+    config::{Config, StaticRecord, parse_fqdn},
+    dns,
+};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{A, AAAA, CNAME, PTR},
+    },
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// TTL of the records answered locally.
+const LOCAL_TTL: u32 = 60;
+
+/// How often the hosts files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bound on the CNAME chain followed inside the local table.
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, Default)]
+struct Table {
+    records: HashMap<Name, Vec<RData>>,
+}
+
+impl Table {
+    fn insert(&mut self, name: Name, rdata: RData) {
+        let rdatas = self.records.entry(name).or_default();
+        if !rdatas.contains(&rdata) {
+            rdatas.push(rdata);
+        }
+    }
+
+    /// Add the entries of a file in `/etc/hosts` format: an address followed by its canonical name and aliases.
+    fn add_hosts(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let Some(addr) = fields.next() else {
+                continue;
+            };
+            // Zone ids such as `fe80::1%lo0` can not be answered over DNS.
+            let Ok(addr) = addr.parse::<IpAddr>() else {
+                log::debug!("Skipping hosts entry \"{}\"", line.trim());
+                continue;
+            };
+            let mut canonical = None;
+            for name in fields {
+                let Ok(name) = parse_fqdn(name) else {
+                    log::debug!("Skipping invalid name \"{}\" in hosts entry", name);
+                    continue;
+                };
+                let rdata = match addr {
+                    IpAddr::V4(addr) => RData::A(A(addr)),
+                    IpAddr::V6(addr) => RData::AAAA(AAAA(addr)),
+                };
+                self.insert(name.clone(), rdata);
+                canonical.get_or_insert(name);
+            }
+            if let Some(canonical) = canonical {
+                self.insert(Name::from(addr), RData::PTR(PTR(canonical)));
+            }
+        }
+    }
+
+    fn add_static(&mut self, records: &[StaticRecord]) {
+        for record in records {
+            self.insert(record.name.clone(), record.rdata.clone());
+        }
+    }
+}
+
+/// Names answered from hosts files and statically configured records, without going through the proxy.
+#[derive(Debug, Default)]
+pub(crate) struct LocalRecords {
+    hosts_files: Vec<PathBuf>,
+    static_records: Vec<StaticRecord>,
+    table: RwLock<Arc<Table>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl LocalRecords {
+    pub(crate) fn new(config: &Config) -> Self {
+        let local = LocalRecords {
+            hosts_files: config.hosts_file.clone(),
+            static_records: config.static_records.clone(),
+            ..Default::default()
+        };
+        local.reload();
+        local
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        self.hosts_files.iter().map(modified).collect()
+    }
+
+    fn reload(&self) {
+        let modified = self.modified_times();
+        let mut table = Table::default();
+        for path in &self.hosts_files {
+            match std::fs::read_to_string(path) {
+                Ok(content) => table.add_hosts(&content),
+                Err(e) => log::warn!("Hosts file {} error \"{}\"", path.display(), e),
+            }
+        }
+        // Static records come last so they are listed after the hosts file addresses for the same name.
+        table.add_static(&self.static_records);
+        log::debug!("Loaded {} local names", table.records.len());
+
+        if let Ok(mut lock) = self.table.write() {
+            *lock = Arc::new(table);
+        }
+        if let Ok(mut lock) = self.modified.lock() {
+            *lock = modified;
+        }
+    }
+
+    /// Reload the hosts files whenever one of them changes, until shutdown.
+    pub(crate) async fn watch(self: Arc<Self>, shutdown_token: tokio_util::sync::CancellationToken) -> socks5_impl::Result<()> {
+        if self.hosts_files.is_empty() {
+            shutdown_token.cancelled().await;
+            return Ok(());
+        }
+        loop {
+            tokio::select! {
+                _ = shutdown_token.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+            let changed = self
+                .modified
+                .lock()
+                .map(|modified| *modified != self.modified_times())
+                .unwrap_or(false);
+            if changed {
+                log::info!("Hosts file changed, reloading");
+                self.reload();
+            }
+        }
+    }
+
+    /// Answer a query from the local records, `None` when the name is not known locally.
+    ///
+    /// A known name without records of the queried type gets an empty NOERROR (NODATA) answer.
+    pub(crate) fn lookup(&self, query: &Message) -> Option<Message> {
+        let question = query.queries.first()?;
+        let table = self.table.read().ok()?.clone();
+        let mut rdatas = table.records.get(question.name())?;
+
+        let query_type = question.query_type();
+        let mut response = dns::build_response(query, ResponseCode::NoError);
+        let mut name = question.name().clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let matched = rdatas
+                .iter()
+                .filter(|rdata| query_type == RecordType::ANY || rdata.record_type() == query_type);
+            let answers = matched
+                .map(|rdata| Record::from_rdata(name.clone(), LOCAL_TTL, rdata.clone()))
+                .collect::<Vec<_>>();
+            if !answers.is_empty() {
+                response.add_answers(answers);
+                break;
+            }
+            let Some(target) = rdatas.iter().find_map(|rdata| match rdata {
+                RData::CNAME(CNAME(target)) => Some(target.clone()),
+                _ => None,
+            }) else {
+                break;
+            };
+            response.add_answer(Record::from_rdata(name, LOCAL_TTL, RData::CNAME(CNAME(target.clone()))));
+            name = target;
+            // A target outside the local table is left to the client to resolve.
+            let Some(next) = table.records.get(&name) else {
+                break;
+            };
+            rdatas = next;
+        }
+        Some(response)
+    }
+}
@@ -0,0 +1,182 @@
+//! A minimal HTTP/1.1 server for the monitoring and admin endpoints, one request per connection.
+
+use socks5_impl::Result;
+use std::{future::Future, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Take},
+    net::{TcpListener, TcpStream},
+};
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request head accepted, the endpoints take no large headers.
+const MAX_HEAD_SIZE: usize = 8192;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) query: String,
+    /// The header fields, names in lowercase.
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// The value of a header field, `name` in lowercase.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The decoded value of a parameter of the query string.
+    pub(crate) fn param(&self, name: &str) -> Option<String> {
+        let decode = |s: &str| {
+            percent_encoding::percent_decode_str(&s.replace('+', " "))
+                .decode_utf8_lossy()
+                .into_owned()
+        };
+        self.query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key) == name).then(|| decode(value))
+        })
+    }
+}
+
+pub(crate) struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, content_type: &'static str, body: String) -> Self {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub(crate) fn not_found() -> Self {
+        Response::new(404, "text/plain", "not found\n".to_owned())
+    }
+
+    pub(crate) fn method_not_allowed() -> Self {
+        Response::new(405, "text/plain", "method not allowed\n".to_owned())
+    }
+
+    pub(crate) fn bad_request(message: &str) -> Self {
+        Response::new(400, "text/plain", format!("{message}\n"))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            431 => "Request Header Fields Too Large",
+            _ => "",
+        }
+    }
+}
+
+/// Answer the requests of every connection with `handler`, until shutdown.
+pub(crate) async fn serve<H, F>(listener: TcpListener, handler: H, shutdown_token: tokio_util::sync::CancellationToken) -> Result<()>
+where
+    H: Fn(Request) -> F + Clone + Send + 'static,
+    F: Future<Output = Response> + Send,
+{
+    let listen_addr = listener.local_addr()?;
+    log::info!("HTTP listening on: {}", listen_addr);
+
+    loop {
+        tokio::select! {
+            _ = shutdown_token.cancelled() => {
+                log::info!("HTTP shutdown received");
+                return Ok(());
+            }
+            res = listener.accept() => {
+                let (stream, _) = match res {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        log::error!("HTTP listener {} error \"{}\"", listen_addr, e);
+                        return Err(e.into());
+                    }
+                };
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, handler).await {
+                        log::debug!("HTTP error \"{}\"", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+async fn handle_connection<H, F>(stream: TcpStream, handler: H) -> Result<()>
+where
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let mut stream = BufReader::new(stream);
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await?? {
+        Ok(request) => handler(request).await,
+        Err(response) => response,
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the request line and the headers, or the response to a request that can not be parsed.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<std::result::Result<Request, Response>> {
+    let too_large = || Response::new(431, "text/plain", "request header fields too large\n".to_owned());
+    let mut head = (&mut *stream).take(MAX_HEAD_SIZE as u64);
+    let mut request_line = String::new();
+    if read_head_line(&mut head, &mut request_line).await?.is_none() {
+        return Ok(Err(too_large()));
+    }
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        let Some(len) = read_head_line(&mut head, &mut line).await? else {
+            return Ok(Err(too_large()));
+        };
+        if len == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(Err(Response::bad_request("bad request")));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Ok(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+    }))
+}
+
+/// Read a line of the request head, `None` once the head is longer than [`MAX_HEAD_SIZE`]. Reading through the
+/// limit keeps a single endless line from growing the buffer.
+async fn read_head_line(head: &mut Take<&mut BufReader<TcpStream>>, line: &mut String) -> Result<Option<usize>> {
+    let len = head.read_line(line).await?;
+    Ok((line.ends_with('\n') || head.limit() > 0).then_some(len))
+}
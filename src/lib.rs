@@ -1,77 +1,640 @@
+mod acl;
+mod admin;
 mod android;
 mod api;
+mod blocklist;
 mod config;
+mod config_file;
 mod dns;
+mod dns64;
+mod dnssec;
+mod dnstap;
 mod dump_logger;
-
-use hickory_proto::op::{Message, Query};
+mod ecs;
+mod fakeip;
+mod health;
+mod hosts;
+mod http;
+mod metrics;
+mod middleware;
+mod policy;
+mod querylog;
+mod ratelimit;
+mod reload;
+mod resolver;
+mod server;
+mod socks_resolve;
+mod systemd;
+mod upstream;
+
+use hickory_proto::op::{Message, Query, ResponseCode};
+use ipnet::IpNet;
 use moka::future::Cache;
-use socks5_impl::{
-    Error, Result, client,
-    protocol::{Address, UserKey},
+use ratelimit::RrlAction;
+use socks5_impl::{Error, Result};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
-use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufStream},
-    net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore, watch},
 };
 
+pub use ::async_trait::async_trait;
+pub use ::hickory_proto;
+pub use ::socks5_impl;
 pub use ::tokio_util::sync::CancellationToken;
-pub use api::{dns2socks_start, dns2socks_stop};
-pub use config::{ArgProxy, ArgVerbosity, Config, ProxyType};
+pub use api::{dns2socks_fake_ip_lookup, dns2socks_start, dns2socks_stop};
+pub use config::{AclAction, ArgProxy, ArgVerbosity, BlockAction, Config, EcsMode, ProxyType, RebindAction, StaticRecord};
 pub use dump_logger::dns2socks_set_log_callback;
+pub use fakeip::{fake_ip_contains, fake_ip_lookup};
+pub use middleware::{Action, Middleware, QueryInfo};
+pub use reload::Reloader;
+pub use resolver::Resolver;
+pub use server::{Dns2Socks, Dns2SocksBuilder, Stats};
+pub use upstream::{Socks5Tcp, Socks5Udp, SocksResolve, Upstream};
 
 pub const LIB_NAME: &str = "dns2socks_core";
 
 const MAX_BUFFER_SIZE: usize = 4096;
 
 pub async fn main_entry(config: Config, shutdown_token: tokio_util::sync::CancellationToken) -> Result<()> {
-    log::info!("Starting DNS2Socks listening on {}...", config.listen_addr);
-    let user_key = config.socks5_settings.credentials.clone();
+    main_entry_with_reloader(config, shutdown_token, Reloader::new()).await
+}
+
+/// Like [`main_entry`], with the configuration reloaded whenever `reloader` is given a new one.
+pub async fn main_entry_with_reloader(
+    config: Config,
+    shutdown_token: tokio_util::sync::CancellationToken,
+    reloader: Reloader,
+) -> Result<()> {
+    let server = Dns2Socks::builder()
+        .config(config)
+        .shutdown_token(shutdown_token)
+        .reloader(reloader)
+        .start()
+        .await?;
+    server.wait().await
+}
+
+/// Run a started instance until shutdown or a listener error, applying the reload requests in the meantime.
+async fn serve(
+    mut instance: Instance,
+    mut reload_requests: tokio::sync::mpsc::UnboundedReceiver<reload::ReloadRequest>,
+    state: watch::Sender<server::State>,
+) -> Result<()> {
+    let shutdown_token = instance.shutdown_token.clone();
+    systemd::notify("READY=1");
+    state.send_replace(server::State::Running);
 
-    let timeout = Duration::from_secs(config.timeout);
+    let result = loop {
+        tokio::select! {
+            _ = shutdown_token.cancelled() => {
+                log::info!("Shutdown received");
+                break Ok(());
+            },
+            Some(request) = reload_requests.recv() => {
+                systemd::notify("RELOADING=1");
+                let result = instance.reload(request.config).await;
+                match &result {
+                    Ok(()) => log::info!("Configuration reloaded"),
+                    Err(e) => log::error!("Reload error \"{}\", keeping the running configuration", e),
+                }
+                systemd::notify("READY=1");
+                _ = request.reply.send(result);
+            },
+            Some(res) = instance.tasks.join_next() => match res {
+                // Tasks only end without an error when a reload replaced them.
+                Ok(Ok(())) => continue,
+                res => break res.map_err(|e| e.to_string()).and_then(|res| res.map_err(|e| e.to_string())),
+            },
+        }
+    };
 
-    let cache = create_dns_cache();
-    let shutdown_for_select = shutdown_token.clone();
-    tokio::select! {
-        _ = shutdown_for_select.cancelled() => {
-            log::info!("Shutdown received");
-        },
-        res = udp_thread(config.clone(), user_key.clone(), cache.clone(), timeout, shutdown_token.clone()) => {
-            res?;
-        },
-        res = tcp_thread(config, user_key, cache, timeout, shutdown_token) => {
-            res?;
-        },
+    systemd::notify("STOPPING=1");
+    shutdown_token.cancel();
+    instance.tasks.shutdown().await;
+    let ctx = instance.ctx();
+    drain(&ctx).await;
+    #[cfg(unix)]
+    if let Some(path) = &ctx.config.unix_listen {
+        _ = std::fs::remove_file(path);
+    }
+    if let Some(fake_ip) = &ctx.fake_ip {
+        fake_ip.deactivate();
+        fake_ip.save();
     }
 
+    if ctx.blocklist.blocked() > 0 {
+        log::info!("{} queries answered from the blocklists", ctx.blocklist.blocked());
+    }
+    if ctx.filtered_responses.load(Ordering::Relaxed) > 0 {
+        log::info!(
+            "{} forged or bogus responses dropped",
+            ctx.filtered_responses.load(Ordering::Relaxed)
+        );
+    }
+    if ctx.rebind_blocked.load(Ordering::Relaxed) > 0 {
+        log::info!(
+            "{} answers with private addresses blocked",
+            ctx.rebind_blocked.load(Ordering::Relaxed)
+        );
+    }
+    if ctx.acl.rejected() > 0 {
+        log::info!("{} queries rejected by access control", ctx.acl.rejected());
+    }
+    if let Some(limiter) = &ctx.rate_limiter {
+        log::info!("{} queries dropped by rate limiting", limiter.limited());
+    }
+    if let Some(rrl) = &ctx.rrl {
+        log::info!(
+            "{} responses dropped and {} truncated by response rate limiting",
+            rrl.dropped(),
+            rrl.slipped()
+        );
+    }
     log::info!("DNS2Socks stopped");
+    state.send_replace(server::State::Stopped);
 
-    Ok(())
+    Ok(result?)
 }
 
-pub(crate) async fn udp_thread(
-    opt: Config,
-    user_key: Option<UserKey>,
-    cache: Cache<Vec<Query>, Message>,
-    timeout: Duration,
-    shutdown_token: tokio_util::sync::CancellationToken,
-) -> Result<()> {
-    let listener = match UdpSocket::bind(&opt.listen_addr).await {
-        Ok(listener) => listener,
-        Err(e) => {
-            log::error!("UDP listener {} error \"{}\"", opt.listen_addr, e);
-            return Err(e.into());
-        }
+/// Let the queries being resolved finish, for up to the upstream timeout, once the listeners are stopped.
+async fn drain(ctx: &Context) {
+    let deadline = tokio::time::Instant::now() + ctx.timeout;
+    while ctx.metrics.in_flight_count() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    if ctx.metrics.in_flight_count() > 0 {
+        log::warn!("{} queries still in flight at shutdown", ctx.metrics.in_flight_count());
+    }
+}
+
+/// The context new queries are handled with, replaced as a whole by a reload.
+pub(crate) type SharedContext = watch::Receiver<Arc<Context>>;
+
+/// The tasks of a running instance, in groups that are stopped and started again separately on reload.
+struct Instance {
+    ctx: watch::Sender<Arc<Context>>,
+    tasks: tokio::task::JoinSet<Result<()>>,
+    activated: bool,
+    shutdown_token: CancellationToken,
+    background_token: CancellationToken,
+    ip_token: CancellationToken,
+    unix_token: CancellationToken,
+    metrics_token: CancellationToken,
+    admin_token: CancellationToken,
+    listening: Listening,
+    started: Instant,
+}
+
+/// The addresses served on, by kind of listener, as reported by the admin API.
+pub(crate) type Listening = Arc<std::sync::Mutex<Vec<(&'static str, String)>>>;
+
+/// The groups of listeners a reload binds again.
+#[derive(Clone, Copy)]
+struct Rebind {
+    ip: bool,
+    unix: bool,
+    metrics: bool,
+    admin: bool,
+}
+
+impl Rebind {
+    const ALL: Rebind = Rebind {
+        ip: true,
+        unix: true,
+        metrics: true,
+        admin: true,
     };
+}
+
+impl Instance {
+    fn new(ctx: Arc<Context>, activated: bool, shutdown_token: &CancellationToken) -> Self {
+        Instance {
+            ctx: watch::Sender::new(ctx),
+            tasks: tokio::task::JoinSet::new(),
+            activated,
+            shutdown_token: shutdown_token.clone(),
+            background_token: shutdown_token.child_token(),
+            ip_token: shutdown_token.child_token(),
+            unix_token: shutdown_token.child_token(),
+            metrics_token: shutdown_token.child_token(),
+            admin_token: shutdown_token.child_token(),
+            listening: Listening::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn ctx(&self) -> Arc<Context> {
+        self.ctx.borrow().clone()
+    }
+
+    /// Stop the tasks of a group, and return the token of the tasks replacing them.
+    fn restart(&self, token: &CancellationToken) -> CancellationToken {
+        token.cancel();
+        self.shutdown_token.child_token()
+    }
+
+    /// Start watching the lists and saving the fake-IP mappings of the current context.
+    fn spawn_background(&mut self) {
+        let ctx = self.ctx();
+        self.background_token = self.restart(&self.background_token);
+        let token = &self.background_token;
+        self.tasks.spawn(ctx.local_records.clone().watch(token.clone()));
+        self.tasks.spawn(ctx.blocklist.clone().watch(token.clone()));
+        if let Some(fake_ip) = &ctx.fake_ip {
+            fake_ip.activate();
+            self.tasks.spawn(fake_ip.clone().persist(token.clone()));
+        }
+    }
+
+    /// Serve on the listeners, in place of the ones of the groups to rebind.
+    fn spawn_listeners(&mut self, listeners: Listeners, rebind: Rebind) {
+        self.update_listening(&listeners, rebind);
+        if rebind.ip {
+            self.ip_token = self.restart(&self.ip_token);
+            for listener in listeners.udp {
+                self.tasks.spawn(udp_thread(listener, self.ctx.subscribe(), self.ip_token.clone()));
+            }
+            for listener in listeners.tcp {
+                self.tasks.spawn(tcp_thread(listener, self.ctx.subscribe(), self.ip_token.clone()));
+            }
+        }
+        if rebind.unix {
+            self.unix_token = self.restart(&self.unix_token);
+            #[cfg(unix)]
+            for listener in listeners.unix {
+                self.tasks
+                    .spawn(unix_thread(listener, self.ctx.subscribe(), self.unix_token.clone()));
+            }
+        }
+        if rebind.metrics {
+            self.metrics_token = self.restart(&self.metrics_token);
+            for listener in listeners.metrics {
+                self.tasks
+                    .spawn(metrics::serve(listener, self.ctx.subscribe(), self.metrics_token.clone()));
+            }
+        }
+        if rebind.admin {
+            self.admin_token = self.restart(&self.admin_token);
+            for listener in listeners.admin {
+                let admin = admin::Admin {
+                    ctx: self.ctx.subscribe(),
+                    listening: self.listening.clone(),
+                    started: self.started,
+                    shutdown_token: self.shutdown_token.clone(),
+                };
+                self.tasks.spawn(admin::serve(listener, admin, self.admin_token.clone()));
+            }
+        }
+    }
+
+    /// Replace the addresses of the groups to rebind with the ones of the new listeners, the other groups have none.
+    fn update_listening(&self, listeners: &Listeners, rebind: Rebind) {
+        let Ok(mut listening) = self.listening.lock() else {
+            return;
+        };
+        let rebound = |kind: &str| match kind {
+            "udp" | "tcp" => rebind.ip,
+            "unix" => rebind.unix,
+            "metrics" => rebind.metrics,
+            _ => rebind.admin,
+        };
+        listening.retain(|(kind, _)| !rebound(kind));
+        let local = |kind, addrs: Vec<std::io::Result<SocketAddr>>| addrs.into_iter().flatten().map(move |addr| (kind, addr.to_string()));
+        listening.extend(local("udp", listeners.udp.iter().map(UdpSocket::local_addr).collect()));
+        listening.extend(local("tcp", listeners.tcp.iter().map(TcpListener::local_addr).collect()));
+        listening.extend(listeners.unix_paths().into_iter().map(|path| ("unix", path)));
+        listening.extend(local("metrics", listeners.metrics.iter().map(TcpListener::local_addr).collect()));
+        listening.extend(local("admin", listeners.admin.iter().map(TcpListener::local_addr).collect()));
+    }
+
+    /// Switch to a new configuration. Everything it needs is prepared first, so on error nothing has changed.
+    async fn reload(&mut self, config: Config) -> Result<()> {
+        let old = self.ctx();
+        let ctx = Arc::new(old.reload(config)?);
+
+        // Sockets from systemd stay, whatever the configured address.
+        let rebind = Rebind {
+            ip: !self.activated && ctx.config.listen_addr != old.config.listen_addr,
+            unix: ctx.config.unix_listen != old.config.unix_listen,
+            metrics: ctx.config.metrics_addr != old.config.metrics_addr,
+            admin: ctx.config.admin_addr != old.config.admin_addr,
+        };
+        let mut listeners = Listeners::default();
+        if rebind.ip {
+            listeners.bind_ip(&ctx.config).await?;
+        }
+        if rebind.unix {
+            listeners.bind_unix(&ctx.config)?;
+        }
+        if rebind.metrics {
+            listeners.bind_metrics(&ctx.config).await?;
+        }
+        if rebind.admin {
+            listeners.bind_admin(&ctx.config).await?;
+        }
+
+        self.ctx.send_replace(ctx.clone());
+        self.spawn_background();
+        self.spawn_listeners(listeners, rebind);
+
+        #[cfg(unix)]
+        if rebind.unix
+            && let Some(path) = &old.config.unix_listen
+        {
+            _ = std::fs::remove_file(path);
+        }
+        if let Some(fake_ip) = &old.fake_ip
+            && !ctx.fake_ip.as_ref().is_some_and(|new| Arc::ptr_eq(new, fake_ip))
+        {
+            if ctx.fake_ip.is_none() {
+                fake_ip.deactivate();
+            }
+            fake_ip.save();
+        }
+        Ok(())
+    }
+}
+
+/// State shared by all listeners and the queries they handle.
+pub(crate) struct Context {
+    pub(crate) config: Config,
+    pub(crate) cache: DnsCache,
+    pub(crate) timeout: Duration,
+    pub(crate) acl: Arc<acl::Acl>,
+    pub(crate) rate_limiter: Option<Arc<ratelimit::QueryRateLimiter>>,
+    pub(crate) rrl: Option<Arc<ratelimit::ResponseRateLimiter>>,
+    pub(crate) upstream_permits: Option<Arc<Semaphore>>,
+    pub(crate) tcp_client_permits: Option<Arc<Semaphore>>,
+    pub(crate) udp_query_permits: Option<Arc<Semaphore>>,
+    pub(crate) queue_timeout: Duration,
+    pub(crate) local_records: Arc<hosts::LocalRecords>,
+    pub(crate) blocklist: Arc<blocklist::Blocklist>,
+    pub(crate) fake_ip: Option<Arc<fakeip::FakeIp>>,
+    pub(crate) filtered_responses: Arc<AtomicU64>,
+    pub(crate) rebind_blocked: Arc<AtomicU64>,
+    pub(crate) policies: policy::Policies,
+    pub(crate) dns64: Option<dns64::Dns64>,
+    pub(crate) ecs: ecs::Ecs,
+    pub(crate) dnssec: Option<Arc<dnssec::Validator>>,
+    pub(crate) metrics: Arc<metrics::Metrics>,
+    pub(crate) health: Arc<health::Health>,
+    pub(crate) query_log: Option<Arc<querylog::QueryLog>>,
+    pub(crate) dnstap: Option<Arc<dnstap::Dnstap>>,
+    pub(crate) builtin: upstream::Builtin,
+    /// The upstream given to the builder, instead of the built-in ones.
+    pub(crate) upstream: Option<Arc<dyn Upstream>>,
+    /// The middleware given to the builder, ahead of the built-in stages.
+    pub(crate) middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Context {
+    pub(crate) fn new(config: Config) -> Result<Self> {
+        Self::build(config, None)
+    }
+
+    /// The context of a new configuration. It shares the cache and the counters with this one, and keeps the parts
+    /// whose options are the same along with their state.
+    pub(crate) fn reload(&self, config: Config) -> Result<Self> {
+        Self::build(config, Some(self))
+    }
+
+    fn build(config: Config, previous: Option<&Context>) -> Result<Self> {
+        let fake_ip = match kept(previous, &config, |c| (c.fake_ip.clone(), c.fake_ip_file.clone())) {
+            Some(previous) => previous.fake_ip.clone(),
+            None => {
+                // Mappings saved now are the ones the new pools restore.
+                if let Some(fake_ip) = previous.and_then(|previous| previous.fake_ip.as_ref()) {
+                    fake_ip.save();
+                }
+                fakeip::FakeIp::new(&config).map(Arc::new)
+            }
+        };
+        let filtered_responses = previous.map(|previous| previous.filtered_responses.clone()).unwrap_or_default();
+        let metrics = previous.map(|previous| previous.metrics.clone()).unwrap_or_default();
+        let health = previous.map(|previous| previous.health.clone()).unwrap_or_default();
+        let dnstap = match kept(previous, &config, |c| (c.dnstap_socket.clone(), c.dnstap_file.clone())) {
+            Some(previous) => previous.dnstap.clone(),
+            None => dnstap::Dnstap::new(&config)?.map(Arc::new),
+        };
+        let builtin = upstream::Builtin::new(&config, &metrics, &health, dnstap.as_ref(), &filtered_responses);
+        let client_prefixes = |c: &Config| (c.rate_limit_ipv4_prefix, c.rate_limit_ipv6_prefix);
+        Ok(Context {
+            cache: previous.map_or_else(create_dns_cache, |previous| previous.cache.clone()),
+            timeout: Duration::from_secs(config.timeout),
+            acl: kept(previous, &config, |c| (c.allow.clone(), c.deny.clone(), c.acl_action))
+                .map_or_else(|| Arc::new(acl::Acl::new(&config)), |previous| previous.acl.clone()),
+            rate_limiter: kept(previous, &config, |c| (c.rate_limit, c.rate_limit_burst, client_prefixes(c))).map_or_else(
+                || ratelimit::QueryRateLimiter::new(&config).map(Arc::new),
+                |previous| previous.rate_limiter.clone(),
+            ),
+            rrl: kept(previous, &config, |c| (c.rrl, c.rrl_slip, client_prefixes(c))).map_or_else(
+                || ratelimit::ResponseRateLimiter::new(&config).map(Arc::new),
+                |previous| previous.rrl.clone(),
+            ),
+            upstream_permits: kept(previous, &config, |c| c.max_concurrent_queries).map_or_else(
+                || (config.max_concurrent_queries > 0).then(|| Arc::new(Semaphore::new(config.max_concurrent_queries))),
+                |previous| previous.upstream_permits.clone(),
+            ),
+            tcp_client_permits: kept(previous, &config, |c| c.max_tcp_clients).map_or_else(
+                || (config.max_tcp_clients > 0).then(|| Arc::new(Semaphore::new(config.max_tcp_clients))),
+                |previous| previous.tcp_client_permits.clone(),
+            ),
+            udp_query_permits: kept(previous, &config, |c| c.max_udp_queries).map_or_else(
+                || (config.max_udp_queries > 0).then(|| Arc::new(Semaphore::new(config.max_udp_queries))),
+                |previous| previous.udp_query_permits.clone(),
+            ),
+            queue_timeout: Duration::from_millis(config.queue_timeout),
+            local_records: kept(previous, &config, |c| (c.hosts_file.clone(), c.static_records.clone())).map_or_else(
+                || Arc::new(hosts::LocalRecords::new(&config)),
+                |previous| previous.local_records.clone(),
+            ),
+            blocklist: kept(previous, &config, |c| {
+                (c.blocklist.clone(), c.allowlist.clone(), c.block_action, c.blocklist_reload)
+            })
+            .map_or_else(
+                || Arc::new(blocklist::Blocklist::new(&config)),
+                |previous| previous.blocklist.clone(),
+            ),
+            fake_ip,
+            filtered_responses,
+            rebind_blocked: previous.map(|previous| previous.rebind_blocked.clone()).unwrap_or_default(),
+            metrics,
+            health,
+            query_log: match kept(previous, &config, |c| {
+                let rotation = (c.query_log_max_size, c.query_log_max_age, c.query_log_keep);
+                (c.query_log.clone(), rotation, c.query_log_sample, c.query_log_anonymize)
+            }) {
+                Some(previous) => previous.query_log.clone(),
+                None => querylog::QueryLog::new(&config)?.map(Arc::new),
+            },
+            dnstap,
+            builtin,
+            upstream: previous.and_then(|previous| previous.upstream.clone()),
+            middleware: previous.map(|previous| previous.middleware.clone()).unwrap_or_default(),
+            policies: policy::Policies::new(&config),
+            dns64: dns64::Dns64::new(&config),
+            ecs: ecs::Ecs::new(&config),
+            dnssec: match kept(previous, &config, |c| (c.dnssec, c.dnssec_trust_anchor.clone())) {
+                Some(previous) => previous.dnssec.clone(),
+                None => dnssec::Validator::new(&config)?.map(Arc::new),
+            },
+            config,
+        })
+    }
+}
+
+/// The previous context, when the options a part of the context is built from are the same in `config`.
+fn kept<'a, T: PartialEq>(previous: Option<&'a Context>, config: &Config, options: impl Fn(&Config) -> T) -> Option<&'a Context> {
+    previous.filter(|previous| options(&previous.config) == options(config))
+}
+
+/// The sockets DNS2Socks serves on, either bound from the config or inherited through systemd socket activation.
+#[derive(Default)]
+struct Listeners {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+    #[cfg(unix)]
+    unix: Vec<tokio::net::UnixListener>,
+    metrics: Vec<TcpListener>,
+    admin: Vec<TcpListener>,
+    activated: bool,
+}
+
+impl Listeners {
+    async fn bind(config: &Config) -> std::io::Result<Self> {
+        let mut listeners = Listeners::default();
+        #[cfg(unix)]
+        {
+            listeners.activated = listeners.add_activated()?;
+        }
+
+        if !listeners.activated {
+            listeners.bind_ip(config).await?;
+        } else {
+            log::info!(
+                "Using {} UDP and {} TCP sockets from systemd instead of {}",
+                listeners.udp.len(),
+                listeners.tcp.len(),
+                config.listen_addr
+            );
+        }
+        listeners.bind_unix(config)?;
+        listeners.bind_metrics(config).await?;
+        listeners.bind_admin(config).await?;
+        Ok(listeners)
+    }
+
+    async fn bind_ip(&mut self, config: &Config) -> std::io::Result<()> {
+        let udp = UdpSocket::bind(&config.listen_addr).await.inspect_err(|e| {
+            log::error!("UDP listener {} error \"{}\"", config.listen_addr, e);
+        })?;
+        let tcp = TcpListener::bind(&config.listen_addr).await.inspect_err(|e| {
+            log::error!("TCP listener {} error \"{}\"", config.listen_addr, e);
+        })?;
+        self.udp.push(udp);
+        self.tcp.push(tcp);
+        Ok(())
+    }
+
+    async fn bind_metrics(&mut self, config: &Config) -> std::io::Result<()> {
+        if let Some(addr) = config.metrics_addr {
+            let listener = TcpListener::bind(addr).await.inspect_err(|e| {
+                log::error!("Metrics listener {} error \"{}\"", addr, e);
+            })?;
+            self.metrics.push(listener);
+        }
+        Ok(())
+    }
+
+    async fn bind_admin(&mut self, config: &Config) -> std::io::Result<()> {
+        if let Some(addr) = config.admin_addr {
+            if !addr.ip().is_loopback() && config.admin_token.is_none() {
+                log::warn!(
+                    "The admin API on {} has no --admin-token, any client reaching it that names a loopback Host and sends no Origin controls DNS2Socks",
+                    addr
+                );
+            }
+            let listener = TcpListener::bind(addr).await.inspect_err(|e| {
+                log::error!("Admin listener {} error \"{}\"", addr, e);
+            })?;
+            self.admin.push(listener);
+        }
+        Ok(())
+    }
+
+    /// The paths of the Unix listeners, for the ones bound to one.
+    fn unix_paths(&self) -> Vec<String> {
+        #[cfg(unix)]
+        let paths = self
+            .unix
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .filter_map(|addr| addr.as_pathname().map(|path| path.display().to_string()));
+        #[cfg(not(unix))]
+        let paths = std::iter::empty();
+        paths.collect()
+    }
+
+    fn bind_unix(&mut self, config: &Config) -> std::io::Result<()> {
+        if let Some(path) = &config.unix_listen {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = tokio::net::UnixListener::bind(path).inspect_err(|e| {
+                    log::error!("Unix listener {} error \"{}\"", path.display(), e);
+                })?;
+                self.unix.push(listener);
+            }
+            #[cfg(not(unix))]
+            log::warn!("Unix listener {} is not supported on this platform", path.display());
+        }
+        Ok(())
+    }
+
+    /// Take over the sockets passed in by systemd, returns whether there were any.
+    #[cfg(unix)]
+    fn add_activated(&mut self) -> std::io::Result<bool> {
+        let sockets = systemd::listen_fds()?;
+        let activated = !sockets.is_empty();
+        for socket in sockets {
+            match socket {
+                systemd::ActivatedSocket::Udp(socket) => {
+                    socket.set_nonblocking(true)?;
+                    self.udp.push(UdpSocket::from_std(socket)?);
+                }
+                systemd::ActivatedSocket::Tcp(listener) => {
+                    listener.set_nonblocking(true)?;
+                    self.tcp.push(TcpListener::from_std(listener)?);
+                }
+                systemd::ActivatedSocket::Unix(listener) => {
+                    listener.set_nonblocking(true)?;
+                    self.unix.push(tokio::net::UnixListener::from_std(listener)?);
+                }
+            }
+        }
+        Ok(activated)
+    }
+}
+
+pub(crate) async fn udp_thread(listener: UdpSocket, ctx: SharedContext, shutdown_token: tokio_util::sync::CancellationToken) -> Result<()> {
     let listener = Arc::new(listener);
-    log::info!("Udp listening on: {}", opt.listen_addr);
+    log::info!("Udp listening on: {}", listener.local_addr()?);
 
     loop {
         let listener = listener.clone();
-        let opt = opt.clone();
-        let cache = cache.clone();
-        let auth = user_key.clone();
+        let ctx = &ctx;
         tokio::select! {
             _ = shutdown_token.cancelled() => {
                 log::info!("UDP shutdown received");
@@ -80,9 +643,13 @@ pub(crate) async fn udp_thread(
             res = async move {
                 let mut buf = vec![0u8; MAX_BUFFER_SIZE];
                 let (len, src) = listener.recv_from(&mut buf).await?;
+                let ctx = ctx.borrow().clone();
+                if ctx.rate_limiter.as_ref().is_some_and(|limiter| !limiter.check(src.ip())) {
+                    return Ok(());
+                }
                 buf.resize(len, 0);
                 tokio::spawn(async move {
-                    if let Err(e) = udp_incoming_handler(listener, buf, src, opt, cache, auth, timeout).await {
+                    if let Err(e) = udp_incoming_handler(listener, buf, src, ctx).await {
                         log::error!("DNS query via UDP incoming handler error \"{}\"", e);
                     }
                 });
@@ -97,72 +664,58 @@ pub(crate) async fn udp_thread(
     }
 }
 
-async fn udp_incoming_handler(
-    listener: Arc<UdpSocket>,
-    mut buf: Vec<u8>,
-    src: SocketAddr,
-    opt: Config,
-    cache: Cache<Vec<Query>, Message>,
-    auth: Option<UserKey>,
-    timeout: Duration,
-) -> Result<()> {
-    let message = dns::parse_data_to_dns_message(&buf, false)?;
-    let domain = dns::extract_domain_from_dns_message(&message)?;
-
-    if opt.cache_records
-        && let Some(cached_message) = dns_cache_get_message(&cache, &message).await
-    {
-        let data = cached_message.to_vec().map_err(|e| e.to_string())?;
-        listener.send_to(&data, &src).await?;
-        log_dns_message("DNS query via UDP cache hit", &domain, &cached_message);
+async fn udp_incoming_handler(listener: Arc<UdpSocket>, buf: Vec<u8>, src: SocketAddr, ctx: Arc<Context>) -> Result<()> {
+    let acl_action = ctx.acl.check(src.ip());
+    if acl_action == Some(AclAction::Drop) {
         return Ok(());
     }
 
-    let proxy_addr = opt.socks5_settings.addr;
-    let dest_addr = opt.dns_remote_server;
+    // Queries over the limit are still parsed, so they can be told SERVFAIL instead of being left hanging.
+    let permit = acquire_permit(ctx.udp_query_permits.as_ref(), ctx.queue_timeout).await;
 
-    let data = if opt.force_tcp {
-        let mut new_buf = (buf.len() as u16).to_be_bytes().to_vec();
-        new_buf.append(&mut buf);
-        tcp_via_socks5_server(proxy_addr, dest_addr, auth, &new_buf, timeout)
-            .await
-            .map_err(|e| format!("querying \"{domain}\" {e}"))?
+    let start = Instant::now();
+    let received = SystemTime::now();
+    if let Some(dnstap) = &ctx.dnstap {
+        dnstap.client_query(Some(src), false, received, &buf);
+    }
+    let message = dns::parse_data_to_dns_message(&buf, false)?;
+    let domain = dns::extract_domain_from_dns_message(&message)?;
+
+    let mut info = QueryInfo::new(&ctx, Some(src.ip()), "udp", &domain, ctx.config.force_tcp);
+    let response = if acl_action == Some(AclAction::Refuse) {
+        dns::build_response(&message, ResponseCode::Refused)
+    } else if permit.is_err() {
+        log::warn!("Too many UDP queries, answering {:?} with SERVFAIL", domain);
+        dns::build_response(&message, ResponseCode::ServFail)
     } else {
-        client::ClientWrapper::datagram(proxy_addr, auth)
-            .await
-            .map_err(|e| format!("preparing to query \"{domain}\" {e}"))?
-            .transfer_data(dest_addr, &buf, timeout)
-            .await
-            .map_err(|e| format!("querying \"{domain}\" {e}"))?
+        middleware::resolve(&mut info, &message).await?
     };
-    let message = dns::parse_data_to_dns_message(&data, opt.force_tcp)?;
-    let msg_buf = message.to_vec().map_err(|e| e.to_string())?;
-
-    listener.send_to(&msg_buf, &src).await?;
-
-    let prefix = format!("DNS query via {}", if opt.force_tcp { "TCP" } else { "UDP" });
-    log_dns_message(&prefix, &domain, &message);
-    if opt.cache_records {
-        dns_cache_put_message(&cache, &message).await;
+    record_query(&info, &message, &response, start);
+    if let Some(dnstap) = &ctx.dnstap {
+        dnstap.client_response(Some(src), false, received, &buf, &response);
     }
-    Ok::<(), Error>(())
+    udp_send_message(&listener, src, &response, &ctx).await
+}
+
+/// Send a response to a UDP client, subject to response rate limiting.
+async fn udp_send_message(listener: &UdpSocket, dst: SocketAddr, message: &Message, ctx: &Context) -> Result<()> {
+    let message = match ctx.rrl.as_ref().map_or(RrlAction::Send, |rrl| rrl.check(dst.ip(), message)) {
+        RrlAction::Send => message,
+        RrlAction::Drop => return Ok(()),
+        RrlAction::Slip => &message.truncate(),
+    };
+    let data = message.to_vec().map_err(|e| e.to_string())?;
+    listener.send_to(&data, dst).await?;
+    Ok(())
 }
 
 pub(crate) async fn tcp_thread(
-    opt: Config,
-    user_key: Option<UserKey>,
-    cache: Cache<Vec<Query>, Message>,
-    timeout: Duration,
+    listener: TcpListener,
+    ctx: SharedContext,
     shutdown_token: tokio_util::sync::CancellationToken,
 ) -> Result<()> {
-    let listener = match TcpListener::bind(&opt.listen_addr).await {
-        Ok(listener) => listener,
-        Err(e) => {
-            log::error!("TCP listener {} error \"{}\"", opt.listen_addr, e);
-            return Err(e.into());
-        }
-    };
-    log::info!("TCP listening on: {}", opt.listen_addr);
+    let listen_addr = listener.local_addr()?;
+    log::info!("TCP listening on: {}", listen_addr);
 
     loop {
         tokio::select! {
@@ -171,18 +724,19 @@ pub(crate) async fn tcp_thread(
                 return Ok(());
             }
             res = listener.accept() => {
-                let (mut incoming, _) = match res {
+                let (mut incoming, peer) = match res {
                     Ok(conn) => conn,
                     Err(e) => {
-                        log::error!("TCP listener {} error \"{}\"", opt.listen_addr, e);
+                        log::error!("TCP listener {} error \"{}\"", listen_addr, e);
                         return Err(e.into());
                     }
                 };
-                let opt = opt.clone();
-                let user_key = user_key.clone();
-                let cache = cache.clone();
+                let ctx = ctx.borrow().clone();
+                if ctx.rate_limiter.as_ref().is_some_and(|limiter| !limiter.check(peer.ip())) {
+                    continue;
+                }
                 tokio::spawn(async move {
-                    if let Err(e) = handle_tcp_incoming(&opt, user_key, cache, &mut incoming, timeout).await {
+                    if let Err(e) = handle_tcp_incoming(&ctx, Some(peer), &mut incoming).await {
                         log::error!("TCP error \"{}\"", e);
                     }
                 });
@@ -191,86 +745,196 @@ pub(crate) async fn tcp_thread(
     }
 }
 
-async fn handle_tcp_incoming(
-    opt: &Config,
-    auth: Option<UserKey>,
-    cache: Cache<Vec<Query>, Message>,
-    incoming: &mut TcpStream,
-    timeout: Duration,
+#[cfg(unix)]
+pub(crate) async fn unix_thread(
+    listener: tokio::net::UnixListener,
+    ctx: SharedContext,
+    shutdown_token: tokio_util::sync::CancellationToken,
 ) -> Result<()> {
+    let listen_addr = listener.local_addr()?;
+    let listen_addr = listen_addr.as_pathname().map(|path| path.display().to_string()).unwrap_or_default();
+    log::info!("Unix listening on: {}", listen_addr);
+
+    loop {
+        tokio::select! {
+            _ = shutdown_token.cancelled() => {
+                log::info!("Unix shutdown received");
+                return Ok(());
+            }
+            res = listener.accept() => {
+                let (mut incoming, _) = match res {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        log::error!("Unix listener {} error \"{}\"", listen_addr, e);
+                        return Err(e.into());
+                    }
+                };
+                let ctx = ctx.borrow().clone();
+                tokio::spawn(async move {
+                    // Local sidecars are trusted, the access control lists only apply to IP clients.
+                    if let Err(e) = handle_tcp_incoming(&ctx, None, &mut incoming).await {
+                        log::error!("Unix error \"{}\"", e);
+                    }
+                });
+            }
+        };
+    }
+}
+
+async fn handle_tcp_incoming<S>(ctx: &Context, peer: Option<SocketAddr>, incoming: &mut S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let acl_action = peer.and_then(|peer| ctx.acl.check(peer.ip()));
+    if acl_action == Some(AclAction::Drop) {
+        return Ok(());
+    }
+
+    // Connections over the limit still get their query read, so they can be told SERVFAIL instead of being left hanging.
+    let permit = acquire_permit(ctx.tcp_client_permits.as_ref(), ctx.queue_timeout).await;
+
+    let timeout = ctx.timeout;
     let mut len_buf = [0u8; 2];
     tokio::time::timeout(timeout, incoming.read_exact(&mut len_buf)).await??;
     let len = u16::from_be_bytes(len_buf) as usize;
     let mut msg_buf = vec![0u8; len];
     tokio::time::timeout(timeout, incoming.read_exact(&mut msg_buf)).await??;
 
-    let mut buf = len_buf.to_vec();
-    buf.extend(msg_buf);
-
-    let message = dns::parse_data_to_dns_message(&buf, true)?;
+    let start = Instant::now();
+    let received = SystemTime::now();
+    if let Some(dnstap) = &ctx.dnstap {
+        dnstap.client_query(peer, true, received, &msg_buf);
+    }
+    let message = dns::parse_data_to_dns_message(&msg_buf, false)?;
     let domain = dns::extract_domain_from_dns_message(&message)?;
 
-    if opt.cache_records
-        && let Some(cached_message) = dns_cache_get_message(&cache, &message).await
-    {
-        let data = cached_message.to_vec().map_err(|e| e.to_string())?;
-        let len = u16::try_from(data.len()).map_err(|e| e.to_string())?.to_be_bytes().to_vec();
-        let data = [len, data].concat();
-        incoming.write_all(&data).await?;
-        log_dns_message("DNS query via TCP cache hit", &domain, &cached_message);
-        return Ok(());
+    let transport = if peer.is_some() { "tcp" } else { "unix" };
+    let mut info = QueryInfo::new(ctx, peer.map(|peer| peer.ip()), transport, &domain, true);
+    let response = if acl_action == Some(AclAction::Refuse) {
+        dns::build_response(&message, ResponseCode::Refused)
+    } else if permit.is_err() {
+        log::warn!("Too many TCP clients, answering {:?} with SERVFAIL", domain);
+        dns::build_response(&message, ResponseCode::ServFail)
+    } else {
+        middleware::resolve(&mut info, &message).await?
+    };
+    record_query(&info, &message, &response, start);
+    if let Some(dnstap) = &ctx.dnstap {
+        dnstap.client_response(peer, true, received, &msg_buf, &response);
     }
+    tcp_write_message(incoming, &response).await
+}
 
-    let proxy_addr = opt.socks5_settings.addr;
-    let target_server = opt.dns_remote_server;
-    let response_buf = tcp_via_socks5_server(proxy_addr, target_server, auth, &buf, timeout).await?;
-
-    incoming.write_all(&response_buf).await?;
-
-    let message = dns::parse_data_to_dns_message(&response_buf, true)?;
-    log_dns_message("DNS query via TCP", &domain, &message);
-
-    if opt.cache_records {
-        dns_cache_put_message(&cache, &message).await;
+/// Account for a query answered to a client, in the metrics and the query log.
+fn record_query(info: &QueryInfo, query: &Message, response: &Message, start: Instant) {
+    let ctx = info.ctx;
+    ctx.metrics.count_query(info.transport(), query, response);
+    if let Some(query_log) = &ctx.query_log {
+        query_log.log(info.client(), info.transport(), query, response, &info.trace, start);
     }
-
-    Ok(())
 }
 
-async fn tcp_via_socks5_server<A, B>(
-    proxy_addr: A,
-    target_server: B,
-    auth: Option<UserKey>,
-    buf: &[u8],
-    timeout: Duration,
-) -> Result<Vec<u8>>
+/// Write a DNS message to a stream with its two byte length prefix.
+async fn tcp_write_message<S>(stream: &mut S, message: &Message) -> Result<()>
 where
-    A: ToSocketAddrs,
-    B: Into<Address>,
+    S: AsyncWrite + Unpin,
 {
-    let s5_proxy = tokio::time::timeout(timeout, TcpStream::connect(proxy_addr)).await??;
-    let mut stream = BufStream::new(s5_proxy);
-    let _addr = tokio::time::timeout(timeout, client::connect(&mut stream, target_server, auth)).await??;
+    let data = message.to_vec().map_err(|e| e.to_string())?;
+    let len = u16::try_from(data.len()).map_err(|e| e.to_string())?.to_be_bytes().to_vec();
+    let data = [len, data].concat();
+    stream.write_all(&data).await?;
+    Ok(())
+}
 
-    stream.write_all(buf).await?;
-    stream.flush().await?;
+/// Fit a response to the forwarded query to the query the client sent.
+fn client_response(ctx: &Context, query: &Message, response: Message) -> Message {
+    let response = match ctx.dnssec {
+        Some(_) => dnssec::on_response(query, response),
+        None => response,
+    };
+    ctx.ecs.on_response(query, response)
+}
 
-    // Read the length prefix (2 bytes)
-    let mut len_buf = [0u8; 2];
-    tokio::time::timeout(timeout, stream.read_exact(&mut len_buf)).await??;
-    let len = u16::from_be_bytes(len_buf) as usize;
+/// Resolve a query through the upstream, validating the answer with DNSSEC when enabled.
+async fn query_upstream(ctx: &Context, message: &Message, domain: &str, use_tcp: bool) -> Result<Message> {
+    match &ctx.dnssec {
+        Some(validator) => dnssec::resolve(ctx, validator, message, domain, use_tcp).await,
+        None => send_upstream(ctx, message, domain, use_tcp).await,
+    }
+}
 
-    // Read the DNS message
-    let mut msg_buf = vec![0u8; len];
-    tokio::time::timeout(timeout, stream.read_exact(&mut msg_buf)).await??;
+/// Send a query to the upstream, unless it is marked down, recording its latency and health.
+async fn send_upstream(ctx: &Context, message: &Message, domain: &str, use_tcp: bool) -> Result<Message> {
+    let upstream = select_upstream(ctx, message, use_tcp);
+    let name = upstream.name();
+    if ctx.health.is_down(&name) {
+        return Err(format!("not resolving \"{domain}\", upstream {name} is marked down").into());
+    }
+    let start = tokio::time::Instant::now();
+    let result = upstream.exchange(message.clone()).await;
+    // The built-in upstreams count their own errors, by kind.
+    let result = match (&ctx.upstream, result) {
+        (Some(_), Err(e)) => {
+            ctx.metrics.count_upstream_error(metrics::error_kind(&e));
+            Err(format!("querying \"{domain}\" {e}").into())
+        }
+        (_, result) => result,
+    };
+    ctx.health.upstream_answer(&name, &result);
+    if result.is_ok() {
+        ctx.metrics.observe_upstream(&name, start.elapsed());
+    }
+    result
+}
+
+/// The upstream a query is sent to: the one the instance was built with, or the SOCKS RESOLVE extension, or the
+/// remote DNS server over the transport of the client.
+fn select_upstream<'a>(ctx: &'a Context, message: &Message, use_tcp: bool) -> &'a dyn Upstream {
+    let opt = &ctx.config;
+    match &ctx.upstream {
+        Some(upstream) => upstream.as_ref(),
+        None if opt.socks_resolve && socks_resolve::is_supported(message) => &ctx.builtin.socks_resolve,
+        // Tor has no UDP ASSOCIATE, so the --socks-resolve fallback always goes over TCP.
+        None if use_tcp || opt.socks_resolve => &ctx.builtin.tcp,
+        None => &ctx.builtin.udp,
+    }
+}
 
-    // Prepend the length prefix to match the expected format
-    let mut response_buf = len_buf.to_vec();
-    response_buf.extend(msg_buf);
-    Ok(response_buf)
+/// Keep public names from resolving to private addresses, so a malicious domain can not be used to reach the
+/// local network of the client.
+fn protect_from_rebinding(ctx: &Context, query: &Message, mut response: Message) -> Message {
+    let opt = &ctx.config;
+    let Some(question) = query.queries.first() else {
+        return response;
+    };
+    if !opt.rebind_protection || opt.rebind_allow.iter().any(|zone| zone.zone_of(question.name())) {
+        return response;
+    }
+    if dns::strip_private_answers(&mut response) == 0 {
+        return response;
+    }
+    ctx.rebind_blocked.fetch_add(1, Ordering::Relaxed);
+    log::debug!("Removed private addresses from the answer to {}", question.name());
+    match opt.rebind_action {
+        RebindAction::Strip => response,
+        RebindAction::NxDomain => dns::build_response(query, ResponseCode::NXDomain),
+    }
 }
 
-fn log_dns_message(prefix: &str, domain: &str, message: &Message) {
+/// Wait up to `timeout` for a permit, there is nothing to wait for when no limit is configured.
+async fn acquire_permit(
+    semaphore: Option<&Arc<Semaphore>>,
+    timeout: Duration,
+) -> std::result::Result<Option<OwnedSemaphorePermit>, tokio::time::error::Elapsed> {
+    let Some(semaphore) = semaphore else {
+        return Ok(None);
+    };
+    // The semaphores are never closed, so acquiring can only fail by timing out.
+    let permit = tokio::time::timeout(timeout, semaphore.clone().acquire_owned()).await?;
+    Ok(permit.ok())
+}
+
+pub(crate) fn log_dns_message(prefix: &str, domain: &str, message: &Message) {
     let ipaddr = match dns::extract_ipaddr_from_dns_message(message) {
         Ok(ipaddr) => {
             format!("{:?}", ipaddr)
@@ -280,21 +944,32 @@ fn log_dns_message(prefix: &str, domain: &str, message: &Message) {
     log::trace!("{} {:?} <==> {:?}", prefix, domain, ipaddr);
 }
 
-pub(crate) fn create_dns_cache() -> Cache<Vec<Query>, Message> {
+/// Cached answers, by question and by the client subnet they are valid for, `None` for every client.
+pub(crate) type DnsCache = Cache<(Vec<Query>, Option<IpNet>), Message>;
+
+pub(crate) fn create_dns_cache() -> DnsCache {
     Cache::builder()
         .time_to_live(Duration::from_secs(30 * 60))
         .time_to_idle(Duration::from_secs(5 * 60))
         .build()
 }
 
-pub(crate) async fn dns_cache_get_message(cache: &Cache<Vec<Query>, Message>, message: &Message) -> Option<Message> {
-    if let Some(mut cached_message) = cache.get(&message.queries).await {
+pub(crate) async fn dns_cache_get_message(cache: &DnsCache, message: &Message) -> Option<Message> {
+    let mut cached_message = None;
+    if let Some(subnet) = ecs::query_subnet(message) {
+        cached_message = cache.get(&(message.queries.clone(), Some(subnet))).await;
+    }
+    if cached_message.is_none() {
+        cached_message = cache.get(&(message.queries.clone(), None)).await;
+    }
+    if let Some(mut cached_message) = cached_message {
         cached_message.metadata.id = message.metadata.id;
         return Some(cached_message);
     }
     None
 }
 
-pub(crate) async fn dns_cache_put_message(cache: &Cache<Vec<Query>, Message>, message: &Message) {
-    cache.insert(message.queries.clone(), message.clone()).await;
+pub(crate) async fn dns_cache_put_message(cache: &DnsCache, query: &Message, message: &Message) {
+    let subnet = ecs::cache_scope(query, message);
+    cache.insert((message.queries.clone(), subnet), message.clone()).await;
 }
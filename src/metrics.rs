@@ -0,0 +1,286 @@
+//! Prometheus metrics of the queries, the cache and the upstream, served in the text format at `/metrics` on
+//! `--metrics-addr`.
+
+use crate::{
+    Context, SharedContext, dns,
+    http::{self, Request, Response},
+};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::RecordType,
+};
+use socks5_impl::Error;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+/// Upper bounds of the latency histogram buckets in seconds, the Prometheus client defaults.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn write(&self, out: &mut String, name: &str, label: (&str, &str)) {
+        let (key, value) = label;
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            _ = writeln!(out, "{name}_bucket{{{key}=\"{value}\",le=\"{bound}\"}} {bucket}");
+        }
+        _ = writeln!(out, "{name}_bucket{{{key}=\"{value}\",le=\"+Inf\"}} {}", self.count);
+        _ = writeln!(out, "{name}_sum{{{key}=\"{value}\"}} {}", self.sum);
+        _ = writeln!(out, "{name}_count{{{key}=\"{value}\"}} {}", self.count);
+    }
+}
+
+/// Counters of the running instance, kept across reloads.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    queries: Mutex<HashMap<(&'static str, RecordType, ResponseCode), u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    upstream_errors: Mutex<HashMap<&'static str, u64>>,
+    upstream_latency: Mutex<HashMap<String, Histogram>>,
+    handshake_latency: Mutex<HashMap<&'static str, Histogram>>,
+    in_flight: AtomicI64,
+}
+
+/// A query being resolved, counted in the in-flight gauge until it is dropped.
+pub(crate) struct InFlight<'a>(&'a AtomicI64);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Count a query answered to a client over `transport`.
+    pub(crate) fn count_query(&self, transport: &'static str, query: &Message, response: &Message) {
+        let query_type = query
+            .queries
+            .first()
+            .map_or(RecordType::Unknown(0), |question| question.query_type());
+        if let Ok(mut queries) = self.queries.lock() {
+            *queries.entry((transport, query_type, response.metadata.response_code)).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn count_cache(&self, hit: bool) {
+        let counter = if hit { &self.cache_hits } else { &self.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a failure to get an answer from the upstream, see [`error_kind`].
+    pub(crate) fn count_upstream_error(&self, kind: &'static str) {
+        if let Ok(mut errors) = self.upstream_errors.lock() {
+            *errors.entry(kind).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn observe_upstream(&self, upstream: &str, elapsed: Duration) {
+        if let Ok(mut latency) = self.upstream_latency.lock() {
+            latency.entry(upstream.to_owned()).or_default().observe(elapsed);
+        }
+    }
+
+    /// Record how long connecting to the proxy and completing a SOCKS5 `command` took.
+    pub(crate) fn observe_handshake(&self, command: &'static str, elapsed: Duration) {
+        if let Ok(mut latency) = self.handshake_latency.lock() {
+            latency.entry(command).or_default().observe(elapsed);
+        }
+    }
+
+    /// The cache hits and misses.
+    pub(crate) fn cache_counts(&self) -> (u64, u64) {
+        (self.cache_hits.load(Ordering::Relaxed), self.cache_misses.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn in_flight_count(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed).max(0) as u64
+    }
+
+    /// Queries answered to clients, whatever the transport and the response code.
+    pub(crate) fn queries_total(&self) -> u64 {
+        self.queries.lock().map(|queries| queries.values().sum()).unwrap_or(0)
+    }
+
+    pub(crate) fn upstream_errors_total(&self) -> u64 {
+        self.upstream_errors.lock().map(|errors| errors.values().sum()).unwrap_or(0)
+    }
+
+    pub(crate) fn in_flight(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(&self.in_flight)
+    }
+
+    /// The metrics in the Prometheus text format, along with the counters of the context and the cache size.
+    fn render(&self, ctx: &Context) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "dns2socks_queries_total",
+            "counter",
+            "Queries answered, by transport, query type and response code.",
+        );
+        if let Ok(queries) = self.queries.lock() {
+            for ((transport, query_type, rcode), count) in queries.iter() {
+                let rcode = dns::rcode_name(*rcode);
+                _ = writeln!(
+                    out,
+                    "dns2socks_queries_total{{transport=\"{transport}\",qtype=\"{query_type}\",rcode=\"{rcode}\"}} {count}"
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "dns2socks_cache_hits_total",
+            "counter",
+            "Queries answered from the cache.",
+        );
+        _ = writeln!(out, "dns2socks_cache_hits_total {}", self.cache_hits.load(Ordering::Relaxed));
+        header(
+            &mut out,
+            "dns2socks_cache_misses_total",
+            "counter",
+            "Queries looked up in the cache and not found.",
+        );
+        _ = writeln!(out, "dns2socks_cache_misses_total {}", self.cache_misses.load(Ordering::Relaxed));
+        header(&mut out, "dns2socks_cache_entries", "gauge", "Answers in the cache.");
+        _ = writeln!(out, "dns2socks_cache_entries {}", ctx.cache.entry_count());
+
+        header(
+            &mut out,
+            "dns2socks_upstream_errors_total",
+            "counter",
+            "Failures to get an answer from the upstream, by kind.",
+        );
+        if let Ok(errors) = self.upstream_errors.lock() {
+            for (kind, count) in errors.iter() {
+                _ = writeln!(out, "dns2socks_upstream_errors_total{{kind=\"{kind}\"}} {count}");
+            }
+        }
+
+        let name = "dns2socks_upstream_duration_seconds";
+        header(&mut out, name, "histogram", "Time to get an answer from the upstream, by upstream.");
+        if let Ok(latency) = self.upstream_latency.lock() {
+            for (upstream, histogram) in latency.iter() {
+                histogram.write(&mut out, name, ("upstream", upstream));
+            }
+        }
+        let name = "dns2socks_socks5_handshake_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time to connect to the proxy and complete a SOCKS5 command, by command.",
+        );
+        if let Ok(latency) = self.handshake_latency.lock() {
+            for (command, histogram) in latency.iter() {
+                histogram.write(&mut out, name, ("command", command));
+            }
+        }
+
+        header(&mut out, "dns2socks_in_flight_queries", "gauge", "Queries being resolved.");
+        _ = writeln!(out, "dns2socks_in_flight_queries {}", self.in_flight.load(Ordering::Relaxed));
+
+        let counters = [
+            (
+                "dns2socks_blocked_queries_total",
+                "Queries answered from the blocklists.",
+                ctx.blocklist.blocked(),
+            ),
+            (
+                "dns2socks_dropped_responses_total",
+                "Forged or bogus responses dropped.",
+                ctx.filtered_responses.load(Ordering::Relaxed),
+            ),
+            (
+                "dns2socks_rebind_blocked_total",
+                "Answers with private addresses blocked.",
+                ctx.rebind_blocked.load(Ordering::Relaxed),
+            ),
+            (
+                "dns2socks_acl_rejected_total",
+                "Queries rejected by access control.",
+                ctx.acl.rejected(),
+            ),
+            (
+                "dns2socks_rate_limited_total",
+                "Queries dropped by rate limiting.",
+                ctx.rate_limiter.as_ref().map_or(0, |limiter| limiter.limited()),
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, "counter", help);
+            _ = writeln!(out, "{name} {value}");
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// The kind of an error talking to the proxy or the upstream through it.
+pub(crate) fn error_kind(e: &Error) -> &'static str {
+    use std::io::ErrorKind;
+    match e {
+        Error::Io(e) if e.kind() == ErrorKind::TimedOut => "timeout",
+        Error::Io(e) if e.kind() == ErrorKind::ConnectionRefused => "connection_refused",
+        Error::Io(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe
+            ) =>
+        {
+            "connection_closed"
+        }
+        Error::Io(_) => "io",
+        _ => "proxy",
+    }
+}
+
+/// Serve the metrics of the current context until shutdown.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    ctx: SharedContext,
+    shutdown_token: tokio_util::sync::CancellationToken,
+) -> socks5_impl::Result<()> {
+    let handler = move |request: Request| {
+        let ctx = ctx.borrow().clone();
+        async move {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => Response::new(200, "text/plain; version=0.0.4", ctx.metrics.render(&ctx)),
+                (_, "/metrics") => Response::method_not_allowed(),
+                _ => Response::not_found(),
+            }
+        }
+    };
+    http::serve(listener, handler, shutdown_token).await
+}
@@ -0,0 +1,265 @@
+//! The chain of stages a query goes through once it is accepted, up to the upstream, and its response on the way
+//! back. The middleware given to [`crate::Dns2SocksBuilder::middleware`] comes first, in the order it was added,
+//! followed by the built-in stages: local records, blocklists, fake IP, record type policies, client subnet, cache,
+//! and forwarding.
+//!
+//! Each stage sees the query the previous one passed on, and once a stage answers, the stages the query went through
+//! see the response in reverse order, along with the query as they received it.
+
+use crate::{Context, acquire_permit, dns, dns_cache_get_message, dns_cache_put_message, dns64, dnssec, log_dns_message, querylog};
+use hickory_proto::op::{Message, ResponseCode};
+use socks5_impl::Result;
+use std::net::IpAddr;
+
+/// What a stage does with a query or a response.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Pass the message on, as it was or modified: the query to the next stage, the response to the previous one.
+    Next(Message),
+    /// Answer with this response right away. From `on_query` the next stages do not see the query, from
+    /// `on_response` the previous stages do not see the response.
+    Respond(Message),
+}
+
+/// A stage of the query chain. Both hooks default to passing the message on unchanged, an error drops the query
+/// like a failure of the upstream does.
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync {
+    async fn on_query(&self, _info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        Ok(Action::Next(query))
+    }
+
+    /// `query` is the query as this stage received it.
+    async fn on_response(&self, _info: &mut QueryInfo<'_>, _query: &Message, response: Message) -> Result<Action> {
+        Ok(Action::Next(response))
+    }
+}
+
+/// The query being resolved, beyond its message.
+pub struct QueryInfo<'a> {
+    pub(crate) ctx: &'a Context,
+    client: Option<IpAddr>,
+    transport: &'static str,
+    domain: &'a str,
+    use_tcp: bool,
+    pub(crate) trace: querylog::Trace,
+}
+
+impl<'a> QueryInfo<'a> {
+    pub(crate) fn new(ctx: &'a Context, client: Option<IpAddr>, transport: &'static str, domain: &'a str, use_tcp: bool) -> Self {
+        QueryInfo {
+            ctx,
+            client,
+            transport,
+            domain,
+            use_tcp,
+            trace: querylog::Trace::default(),
+        }
+    }
+
+    /// The address of the client, `None` over a Unix socket and for the in-process resolver.
+    pub fn client(&self) -> Option<IpAddr> {
+        self.client
+    }
+
+    /// How the query arrived: `udp`, `tcp`, `unix` or `resolver`.
+    pub fn transport(&self) -> &'static str {
+        self.transport
+    }
+
+    /// Whether the query is forwarded over TCP.
+    pub fn use_tcp(&self) -> bool {
+        self.use_tcp
+    }
+
+    fn log(&self, answered_by: &str, response: &Message) {
+        let transport = if self.use_tcp { "TCP" } else { "UDP" };
+        log_dns_message(&format!("DNS query via {transport} {answered_by}"), self.domain, response);
+    }
+}
+
+impl std::fmt::Debug for QueryInfo<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryInfo")
+            .field("client", &self.client)
+            .field("transport", &self.transport)
+            .field("use_tcp", &self.use_tcp)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The built-in stages, in the order they apply.
+const STAGES: [&dyn Middleware; 7] = [&LocalRecords, &Blocklist, &FakeIp, &Policies, &ClientSubnet, &Cache, &Forward];
+
+/// Answer a query through the chain of `info.ctx`.
+pub(crate) async fn resolve(info: &mut QueryInfo<'_>, message: &Message) -> Result<Message> {
+    let ctx = info.ctx;
+    let _in_flight = ctx.metrics.in_flight();
+    let stages = ctx.middleware.iter().map(|stage| stage.as_ref()).chain(STAGES).collect::<Vec<_>>();
+
+    // The query each stage received, for the stages the response goes back through.
+    let mut received = Vec::with_capacity(stages.len());
+    let mut query = message.clone();
+    let mut response = None;
+    for stage in &stages {
+        match stage.on_query(info, query.clone()).await? {
+            Action::Next(next) => received.push(std::mem::replace(&mut query, next)),
+            Action::Respond(answer) => {
+                response = Some(answer);
+                break;
+            }
+        }
+    }
+    // Forwarding always answers, only a query passed on by every stage has no response.
+    let mut response = response.ok_or("no stage answered the query")?;
+    for (stage, query) in stages[..received.len()].iter().zip(received).rev() {
+        match stage.on_response(info, &query, response).await? {
+            Action::Next(next) => response = next,
+            Action::Respond(answer) => return Ok(answer),
+        }
+    }
+    Ok(response)
+}
+
+/// Answers from the hosts file and the static records.
+struct LocalRecords;
+
+#[async_trait::async_trait]
+impl Middleware for LocalRecords {
+    async fn on_query(&self, info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        Ok(match info.ctx.local_records.lookup(&query) {
+            Some(response) => {
+                info.log("local", &response);
+                Action::Respond(response)
+            }
+            None => Action::Next(query),
+        })
+    }
+}
+
+struct Blocklist;
+
+#[async_trait::async_trait]
+impl Middleware for Blocklist {
+    async fn on_query(&self, info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        Ok(match info.ctx.blocklist.check(&query) {
+            Some(response) => {
+                info.log("blocked", &response);
+                Action::Respond(response)
+            }
+            None => Action::Next(query),
+        })
+    }
+}
+
+struct FakeIp;
+
+#[async_trait::async_trait]
+impl Middleware for FakeIp {
+    async fn on_query(&self, info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        Ok(match info.ctx.fake_ip.as_ref().and_then(|fake_ip| fake_ip.resolve(&query)) {
+            Some(response) => {
+                info.log("fake IP", &response);
+                Action::Respond(response)
+            }
+            None => Action::Next(query),
+        })
+    }
+}
+
+/// The record type policies answering queries themselves. Their rewrites of the responses are part of forwarding,
+/// so the cache holds the rewritten responses.
+struct Policies;
+
+#[async_trait::async_trait]
+impl Middleware for Policies {
+    async fn on_query(&self, info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        Ok(match info.ctx.policies.on_query(&query) {
+            Some(response) => {
+                info.log("policy", &response);
+                Action::Respond(response)
+            }
+            None => Action::Next(query),
+        })
+    }
+}
+
+/// The client subnet option of the forwarded query, and the fit of the response to what the client asked for.
+struct ClientSubnet;
+
+#[async_trait::async_trait]
+impl Middleware for ClientSubnet {
+    async fn on_query(&self, info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        Ok(Action::Next(info.ctx.ecs.on_query(&query).into_owned()))
+    }
+
+    async fn on_response(&self, info: &mut QueryInfo<'_>, query: &Message, response: Message) -> Result<Action> {
+        Ok(Action::Next(crate::client_response(info.ctx, query, response)))
+    }
+}
+
+/// Answers from the cache. Forwarding fills it in.
+struct Cache;
+
+#[async_trait::async_trait]
+impl Middleware for Cache {
+    async fn on_query(&self, info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        let ctx = info.ctx;
+        if !ctx.config.cache_records || !dnssec::cacheable(ctx, &query) {
+            return Ok(Action::Next(query));
+        }
+        let cached_message = dns_cache_get_message(&ctx.cache, &query).await;
+        ctx.metrics.count_cache(cached_message.is_some());
+        info.trace.cache_hit = Some(cached_message.is_some());
+        Ok(match cached_message {
+            Some(cached_message) => {
+                info.log("cache hit", &cached_message);
+                Action::Respond(cached_message)
+            }
+            None => Action::Next(query),
+        })
+    }
+}
+
+/// Resolve the query through the upstream, with DNS64 and rebinding protection, and cache the response.
+struct Forward;
+
+#[async_trait::async_trait]
+impl Middleware for Forward {
+    async fn on_query(&self, info: &mut QueryInfo<'_>, query: Message) -> Result<Action> {
+        let (ctx, domain, use_tcp) = (info.ctx, info.domain, info.use_tcp);
+        let opt = &ctx.config;
+        if opt.socks_resolve && !opt.socks_resolve_fallback && !crate::socks_resolve::is_supported(&query) {
+            log::debug!("Query {:?} can not be answered with SOCKS RESOLVE", domain);
+            return Ok(Action::Respond(dns::build_response(&query, ResponseCode::NotImp)));
+        }
+
+        let Ok(_permit) = acquire_permit(ctx.upstream_permits.as_ref(), ctx.queue_timeout).await else {
+            log::warn!("Too many queries in flight, answering {:?} with SERVFAIL", domain);
+            return Ok(Action::Respond(dns::build_response(&query, ResponseCode::ServFail)));
+        };
+
+        let dns64_ptr = match &ctx.dns64 {
+            Some(dns64) => dns64::resolve_ptr(ctx, dns64, &query, domain, use_tcp).await?,
+            None => None,
+        };
+        let response = match dns64_ptr {
+            Some(response) => response,
+            None => crate::query_upstream(ctx, &query, domain, use_tcp).await?,
+        };
+        let response = match &ctx.dns64 {
+            Some(dns64) => dns64::synthesize(ctx, dns64, &query, domain, use_tcp, response).await?,
+            None => response,
+        };
+        info.trace.upstream = Some(crate::select_upstream(ctx, &query, use_tcp).name());
+        info.trace.proxy = ctx.upstream.is_none().then_some(opt.socks5_settings.addr);
+
+        let response = crate::protect_from_rebinding(ctx, &query, response);
+        let response = ctx.policies.on_response(&query, response);
+
+        if opt.cache_records && dnssec::cacheable(ctx, &query) && !dnssec::is_failure(&response) {
+            dns_cache_put_message(&ctx.cache, &query, &response).await;
+        }
+        Ok(Action::Respond(response))
+    }
+}
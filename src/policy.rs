@@ -0,0 +1,125 @@
+//! Record type policies, applied as a chain of stages to the queries resolved through the proxy and their responses.
+
+use crate::{config::Config, dns};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{
+            HINFO,
+            svcb::{SVCB, SvcParamKey},
+        },
+    },
+};
+
+/// TTL of the minimal answer to ANY queries.
+const ANY_TTL: u32 = 3600;
+
+/// A policy stage. Every hook defaults to letting the query or response through unchanged.
+pub(crate) trait Policy: Send + Sync {
+    /// Answer a query locally instead of resolving it, `None` to let it through.
+    fn on_query(&self, _query: &Message) -> Option<Message> {
+        None
+    }
+
+    /// Rewrite the response to a query resolved through the proxy.
+    fn on_response(&self, _query: &Message, response: Message) -> Message {
+        response
+    }
+}
+
+/// Answer AAAA queries with NODATA, for every name or only for some domains, so clients behind an IPv4-only exit
+/// do not try IPv6 destinations they can not reach.
+struct FilterAaaa {
+    /// Filter every name when empty, otherwise only names in these domains.
+    domains: Vec<Name>,
+}
+
+impl Policy for FilterAaaa {
+    fn on_query(&self, query: &Message) -> Option<Message> {
+        let question = query.queries.first()?;
+        if question.query_type() != RecordType::AAAA {
+            return None;
+        }
+        if !self.domains.is_empty() && !self.domains.iter().any(|zone| zone.zone_of(question.name())) {
+            return None;
+        }
+        Some(dns::build_response(query, ResponseCode::NoError))
+    }
+}
+
+/// Remove the `ipv6hint` parameter of HTTPS and SVCB records.
+struct StripIpv6Hint;
+
+impl StripIpv6Hint {
+    fn strip(svcb: &mut SVCB) {
+        svcb.svc_params.retain(|(key, _)| *key != SvcParamKey::Ipv6Hint);
+    }
+}
+
+impl Policy for StripIpv6Hint {
+    fn on_response(&self, _query: &Message, mut response: Message) -> Message {
+        for record in response.answers.iter_mut().chain(response.additionals.iter_mut()) {
+            match &mut record.data {
+                RData::HTTPS(https) => Self::strip(&mut https.0),
+                RData::SVCB(svcb) => Self::strip(svcb),
+                _ => {}
+            }
+        }
+        response
+    }
+}
+
+/// Answer ANY queries with the minimal HINFO answer of RFC 8482 instead of resolving them.
+struct RefuseAny;
+
+impl Policy for RefuseAny {
+    fn on_query(&self, query: &Message) -> Option<Message> {
+        let question = query.queries.first()?;
+        if question.query_type() != RecordType::ANY {
+            return None;
+        }
+        let mut response = dns::build_response(query, ResponseCode::NoError);
+        let hinfo = HINFO::new("RFC8482".to_owned(), String::new());
+        response.add_answer(Record::from_rdata(question.name().clone(), ANY_TTL, RData::HINFO(hinfo)));
+        Some(response)
+    }
+}
+
+/// The policy stages enabled by the configuration, in the order they apply.
+#[derive(Default)]
+pub(crate) struct Policies {
+    stages: Vec<Box<dyn Policy>>,
+}
+
+impl Policies {
+    pub(crate) fn new(config: &Config) -> Self {
+        let mut stages: Vec<Box<dyn Policy>> = Vec::new();
+        if config.refuse_any {
+            stages.push(Box::new(RefuseAny));
+        }
+        if config.filter_aaaa || !config.filter_aaaa_domain.is_empty() {
+            let domains = if config.filter_aaaa {
+                Vec::new()
+            } else {
+                config.filter_aaaa_domain.clone()
+            };
+            stages.push(Box::new(FilterAaaa { domains }));
+        }
+        if config.strip_ipv6_hint {
+            stages.push(Box::new(StripIpv6Hint));
+        }
+        Policies { stages }
+    }
+
+    /// The answer of the first stage that answers the query itself.
+    pub(crate) fn on_query(&self, query: &Message) -> Option<Message> {
+        self.stages.iter().find_map(|stage| stage.on_query(query))
+    }
+
+    pub(crate) fn on_response(&self, query: &Message, response: Message) -> Message {
+        self.stages
+            .iter()
+            .fold(response, |response, stage| stage.on_response(query, response))
+    }
+}
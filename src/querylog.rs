@@ -0,0 +1,208 @@
+//! The query log: one JSON object per answered query, written by a thread of its own to a file rotated by size and
+//! age, whatever the log level.
+
+use crate::{config::Config, dns};
+use hickory_proto::op::Message;
+use ipnet::IpNet;
+use socks5_impl::Result;
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, SyncSender, TrySendError},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Entries waiting to be written, more are dropped rather than slowing down the queries.
+const QUEUE_SIZE: usize = 4096;
+
+/// Prefix lengths the client addresses are truncated to by --query-log-anonymize.
+const ANONYMIZED_IPV4_PREFIX: u8 = 24;
+const ANONYMIZED_IPV6_PREFIX: u8 = 48;
+
+/// How a query was answered, filled in while it is resolved.
+#[derive(Debug, Default)]
+pub(crate) struct Trace {
+    /// Whether the answer came from the cache, `None` when the cache was not looked up.
+    pub(crate) cache_hit: Option<bool>,
+    /// The upstream and the proxy the query was forwarded to, if it was.
+    pub(crate) upstream: Option<String>,
+    pub(crate) proxy: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub(crate) struct QueryLog {
+    entries: SyncSender<String>,
+    sample: u32,
+    anonymize: bool,
+}
+
+impl QueryLog {
+    /// The query log of the config, the file is opened right away so an unusable path is reported at once.
+    pub(crate) fn new(config: &Config) -> Result<Option<Self>> {
+        let Some(path) = &config.query_log else {
+            return Ok(None);
+        };
+        let writer = Writer::open(path, config).map_err(|e| format!("query log {} error \"{}\"", path.display(), e))?;
+        let (entries, receiver) = std::sync::mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("query-log".to_owned())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Some(QueryLog {
+            entries,
+            sample: config.query_log_sample,
+            anonymize: config.query_log_anonymize,
+        }))
+    }
+
+    /// Log the response to a query received at `start`, from `client` or from a Unix socket client when `None`.
+    pub(crate) fn log(&self, client: Option<IpAddr>, transport: &str, query: &Message, response: &Message, trace: &Trace, start: Instant) {
+        if self.sample > 1 && !rand::random_ratio(1, self.sample) {
+            return;
+        }
+        let client = client.map(|addr| if self.anonymize { anonymize(addr) } else { addr });
+        let entry = entry(client, transport, query, response, trace, start.elapsed());
+        if let Err(TrySendError::Full(_)) = self.entries.try_send(entry) {
+            log::debug!("Query log queue full, dropping an entry");
+        }
+    }
+}
+
+/// The network of an address, without the part identifying the host.
+fn anonymize(addr: IpAddr) -> IpAddr {
+    let prefix = match addr.to_canonical() {
+        IpAddr::V4(_) => ANONYMIZED_IPV4_PREFIX,
+        IpAddr::V6(_) => ANONYMIZED_IPV6_PREFIX,
+    };
+    IpNet::new(addr.to_canonical(), prefix).map_or(addr, |net| net.network())
+}
+
+fn entry(client: Option<IpAddr>, transport: &str, query: &Message, response: &Message, trace: &Trace, latency: Duration) -> String {
+    let question = query.queries.first();
+    let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let mut out = String::from("{");
+    _ = write!(out, "\"timestamp\":{}", json_string(&timestamp));
+    _ = write!(out, ",\"client\":{}", json_option(client));
+    _ = write!(out, ",\"qname\":{}", json_option(question.map(|question| question.name())));
+    _ = write!(out, ",\"qtype\":{}", json_option(question.map(|question| question.query_type())));
+    _ = write!(out, ",\"transport\":{}", json_string(transport));
+    let cache = trace.cache_hit.map(|hit| if hit { "hit" } else { "miss" });
+    _ = write!(out, ",\"cache\":{}", json_option(cache));
+    _ = write!(out, ",\"upstream\":{}", json_option(trace.upstream.as_ref()));
+    _ = write!(out, ",\"proxy\":{}", json_option(trace.proxy));
+    _ = write!(out, ",\"rcode\":{}", json_string(&dns::rcode_name(response.metadata.response_code)));
+    out.push_str(",\"answers\":[");
+    for (i, answer) in response.answers.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        _ = write!(
+            out,
+            "{{\"name\":{},\"type\":{},\"ttl\":{},\"data\":{}}}",
+            json_string(&answer.name.to_string()),
+            json_string(&answer.record_type().to_string()),
+            answer.ttl,
+            json_string(&answer.data.to_string())
+        );
+    }
+    _ = write!(out, "],\"latency_ms\":{:.3}}}", latency.as_secs_f64() * 1000.0);
+    out
+}
+
+pub(crate) fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_owned(), |value| json_string(&value.to_string()))
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The log file, moved to `<path>.1` and so on once it is too large or too old.
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    keep: usize,
+    file: BufWriter<File>,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl Writer {
+    fn open(path: &Path, config: &Config) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        Ok(Writer {
+            path: path.to_owned(),
+            max_size: config.query_log_max_size * 1024 * 1024,
+            max_age: Duration::from_secs(config.query_log_max_age * 3600),
+            keep: config.query_log_keep,
+            size: meta.len(),
+            opened: meta.created().unwrap_or_else(|_| SystemTime::now()),
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// Write the entries as they come, until every sender is gone.
+    fn run(mut self, entries: Receiver<String>) {
+        while let Ok(entry) = entries.recv() {
+            let mut result = self.write(&entry);
+            while let Ok(entry) = entries.try_recv() {
+                result = result.and_then(|_| self.write(&entry));
+            }
+            if let Err(e) = result.and_then(|_| self.file.flush()) {
+                log::warn!("Query log {} error \"{}\"", self.path.display(), e);
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &str) -> std::io::Result<()> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+        self.file.write_all(entry.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += entry.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> bool {
+        let too_large = self.max_size > 0 && self.size >= self.max_size;
+        let too_old = !self.max_age.is_zero() && self.opened.elapsed().is_ok_and(|age| age >= self.max_age);
+        self.size > 0 && (too_large || too_old)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            _ = std::fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                _ = std::fs::rename(rotated(n), rotated(n + 1));
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.size = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use crate::config::Config;
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::RecordType,
+};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// How often idle buckets are swept out of the table.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Most buckets in a table, so spoofed source addresses can not grow it without limit between two sweeps.
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// Number of requests refused in a row, used for the RRL slip.
+    refused: u32,
+}
+
+impl Bucket {
+    fn new(tokens: f64, now: Instant) -> Self {
+        Bucket {
+            tokens,
+            last: now,
+            refused: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    map: HashMap<K, Bucket>,
+    /// Shared by the keys that arrive while the table is full.
+    overflow: Bucket,
+    last_prune: Instant,
+}
+
+/// A table of token buckets, one per key, each refilled at `rate` tokens per second up to `burst`.
+#[derive(Debug)]
+struct TokenBuckets<K> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets<K>>,
+}
+
+impl<K: Hash + Eq> TokenBuckets<K> {
+    fn new(rate: u32, burst: u32) -> Self {
+        TokenBuckets {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                overflow: Bucket::new(burst.max(1) as f64, Instant::now()),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token for `key`. Returns `None` when one was available, otherwise the number of requests
+    /// refused in a row for this key, including this one.
+    fn take(&self, key: K) -> Option<u32> {
+        let now = Instant::now();
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return None;
+        };
+
+        if now.duration_since(buckets.last_prune) >= PRUNE_INTERVAL {
+            let (rate, burst) = (self.rate, self.burst);
+            // A bucket that has refilled completely behaves exactly like a missing one.
+            buckets
+                .map
+                .retain(|_, b| b.tokens + now.duration_since(b.last).as_secs_f64() * rate < burst);
+            buckets.last_prune = now;
+        }
+
+        let buckets = &mut *buckets;
+        let bucket = match buckets.map.len() < MAX_BUCKETS || buckets.map.contains_key(&key) {
+            true => buckets.map.entry(key).or_insert(Bucket::new(self.burst, now)),
+            false => &mut buckets.overflow,
+        };
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * self.rate).min(self.burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.refused = 0;
+            None
+        } else {
+            bucket.refused = bucket.refused.saturating_add(1);
+            Some(bucket.refused)
+        }
+    }
+}
+
+/// Mask a client address down to the prefix it is accounted under.
+fn client_prefix(addr: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    match addr.to_canonical() {
+        IpAddr::V4(addr) => ipnet::Ipv4Net::new(addr, ipv4_prefix.min(32))
+            .map(|net| net.network().into())
+            .unwrap_or(addr.into()),
+        IpAddr::V6(addr) => ipnet::Ipv6Net::new(addr, ipv6_prefix.min(128))
+            .map(|net| net.network().into())
+            .unwrap_or(addr.into()),
+    }
+}
+
+/// Per client (or client prefix) limit on the number of queries accepted per second.
+#[derive(Debug)]
+pub(crate) struct QueryRateLimiter {
+    buckets: TokenBuckets<IpAddr>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    limited: AtomicU64,
+}
+
+impl QueryRateLimiter {
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        let rate = config.rate_limit.filter(|rate| *rate > 0)?;
+        Some(QueryRateLimiter {
+            buckets: TokenBuckets::new(rate, config.rate_limit_burst.unwrap_or(rate)),
+            ipv4_prefix: config.rate_limit_ipv4_prefix,
+            ipv6_prefix: config.rate_limit_ipv6_prefix,
+            limited: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns whether a query from `addr` may be served.
+    pub(crate) fn check(&self, addr: IpAddr) -> bool {
+        let prefix = client_prefix(addr, self.ipv4_prefix, self.ipv6_prefix);
+        if self.buckets.take(prefix).is_none() {
+            return true;
+        }
+        self.limited.fetch_add(1, Ordering::Relaxed);
+        log::debug!("Client {} exceeded the query rate limit", addr);
+        false
+    }
+
+    pub(crate) fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do with a UDP response after response rate limiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RrlAction {
+    Send,
+    Drop,
+    /// Send a truncated response instead, so a legitimate client retries over TCP.
+    Slip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ResponseKind {
+    Answer,
+    NxDomain,
+    Error,
+}
+
+/// The responses accounted together. Like BIND does, NXDOMAIN responses are accounted by zone, so random names in
+/// it are limited together, and errors by client alone.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct RrlKey {
+    client: IpAddr,
+    kind: ResponseKind,
+    name: String,
+    qtype: u16,
+}
+
+/// BIND style response rate limiting for UDP: identical responses to the same client prefix are limited per
+/// second, so the listener is useless for reflection and amplification attacks.
+#[derive(Debug)]
+pub(crate) struct ResponseRateLimiter {
+    buckets: TokenBuckets<RrlKey>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    slip: u32,
+    dropped: AtomicU64,
+    slipped: AtomicU64,
+}
+
+impl ResponseRateLimiter {
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        let rate = config.rrl.filter(|rate| *rate > 0)?;
+        Some(ResponseRateLimiter {
+            buckets: TokenBuckets::new(rate, rate),
+            ipv4_prefix: config.rate_limit_ipv4_prefix,
+            ipv6_prefix: config.rate_limit_ipv6_prefix,
+            slip: config.rrl_slip,
+            dropped: AtomicU64::new(0),
+            slipped: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn check(&self, addr: IpAddr, response: &Message) -> RrlAction {
+        let kind = match response.metadata.response_code {
+            ResponseCode::NoError => ResponseKind::Answer,
+            ResponseCode::NXDomain => ResponseKind::NxDomain,
+            _ => ResponseKind::Error,
+        };
+        let query = response.queries.first();
+        let (name, qtype) = match (kind, query) {
+            (ResponseKind::Answer, Some(query)) => (query.name().to_lowercase().to_string(), u16::from(query.query_type())),
+            // The owner of the SOA is the zone, without one the parent of the name stands for it.
+            (ResponseKind::NxDomain, Some(query)) => {
+                let soa = response.authorities.iter().find(|record| record.record_type() == RecordType::SOA);
+                let zone = soa.map_or_else(|| query.name().base_name(), |soa| soa.name.clone());
+                (zone.to_lowercase().to_string(), 0)
+            }
+            _ => (String::new(), 0),
+        };
+        let key = RrlKey {
+            client: client_prefix(addr, self.ipv4_prefix, self.ipv6_prefix),
+            kind,
+            name,
+            qtype,
+        };
+        let Some(refused) = self.buckets.take(key) else {
+            return RrlAction::Send;
+        };
+        if self.slip > 0 && refused % self.slip == 0 {
+            self.slipped.fetch_add(1, Ordering::Relaxed);
+            RrlAction::Slip
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            RrlAction::Drop
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn slipped(&self) -> u64 {
+        self.slipped.load(Ordering::Relaxed)
+    }
+}
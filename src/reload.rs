@@ -0,0 +1,58 @@
+//! Applying a new configuration to a running instance, without restarting it.
+
+use crate::config::Config;
+use socks5_impl::Result;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// A configuration waiting to be applied, and where to report whether it was.
+pub(crate) struct ReloadRequest {
+    pub(crate) config: Config,
+    pub(crate) reply: oneshot::Sender<Result<()>>,
+}
+
+/// Reloads the configuration of the instance started with it by [`crate::main_entry_with_reloader`] or
+/// [`crate::Dns2SocksBuilder::reloader`].
+///
+/// A new configuration applies to the queries received after it, all at once, while the queries in flight finish
+/// with the previous one. The cache is kept, and so are the lists, fake-IP mappings and rate limits whose options did
+/// not change. The listeners are only rebound when `listen_addr` or `unix_listen` change.
+#[derive(Debug, Clone)]
+pub struct Reloader {
+    requests: mpsc::UnboundedSender<ReloadRequest>,
+    receiver: Arc<Mutex<Option<mpsc::UnboundedReceiver<ReloadRequest>>>>,
+}
+
+impl Default for Reloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reloader {
+    pub fn new() -> Self {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        Reloader {
+            requests,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+        }
+    }
+
+    /// Apply `config` to the running instance. On error the instance keeps running with its current configuration.
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        if self.receiver.lock().map(|receiver| receiver.is_some()).unwrap_or(false) {
+            return Err("DNS2Socks is not running".into());
+        }
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send(ReloadRequest { config, reply })
+            .map_err(|_| "DNS2Socks is not running")?;
+        result.await.map_err(|_| "DNS2Socks stopped before reloading")?
+    }
+
+    /// The requests of the instance this reloader belongs to, an instance can only be started once with it.
+    pub(crate) fn take_requests(&self) -> Result<mpsc::UnboundedReceiver<ReloadRequest>> {
+        let receiver = self.receiver.lock().ok().and_then(|mut receiver| receiver.take());
+        receiver.ok_or_else(|| "the reloader is already used by another instance".into())
+    }
+}
@@ -0,0 +1,124 @@
+//! Resolving names in-process, through the same local records, policies, cache, upstream and proxy the listeners use,
+//! without sending DNS over loopback.
+
+use crate::{Context, QueryInfo, SharedContext, Upstream, config::Config, dns, middleware};
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{RData, Record, RecordType},
+};
+use socks5_impl::Result;
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::sync::watch;
+
+/// The resolver of the instance started by `dns2socks_start`, answering `dns2socks_resolve`.
+static ACTIVE: Mutex<Option<(Resolver, tokio::runtime::Handle)>> = Mutex::new(None);
+
+/// Resolves names through the proxy from within the program, see [`crate::Dns2Socks::resolver`].
+///
+/// The queries are counted in the metrics and logged to the query log with the `resolver` transport. Access control
+/// and rate limiting do not apply.
+#[derive(Clone)]
+pub struct Resolver {
+    ctx: SharedContext,
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver").finish_non_exhaustive()
+    }
+}
+
+impl Resolver {
+    /// A resolver of its own, for a program that resolves names through the proxy without serving DNS. The lists are
+    /// loaded once, they are not watched for changes.
+    pub fn new(config: Config) -> Result<Self> {
+        let ctx = Arc::new(Context::new(config)?);
+        Ok(Resolver {
+            ctx: watch::channel(ctx).1,
+        })
+    }
+
+    /// A resolver of its own sending the queries that are not answered locally to `upstream`, for instance a mock
+    /// one in tests.
+    pub fn with_upstream(config: Config, upstream: Arc<dyn Upstream>) -> Result<Self> {
+        let mut ctx = Context::new(config)?;
+        ctx.upstream = Some(upstream);
+        Ok(Resolver {
+            ctx: watch::channel(Arc::new(ctx)).1,
+        })
+    }
+
+    /// A resolver following the context of a running instance, and its reloads.
+    pub(crate) fn with_context(ctx: SharedContext) -> Self {
+        Resolver { ctx }
+    }
+
+    /// Answer a DNS query message, whatever its response code.
+    pub async fn query(&self, message: Message) -> Result<Message> {
+        let ctx = self.ctx.borrow().clone();
+        let start = Instant::now();
+        let domain = dns::extract_domain_from_dns_message(&message)?;
+        let mut info = QueryInfo::new(&ctx, None, "resolver", &domain, ctx.config.force_tcp);
+        let response = middleware::resolve(&mut info, &message).await?;
+        crate::record_query(&info, &message, &response, start);
+        Ok(response)
+    }
+
+    /// The answers of type `record_type` for `name`, fails unless the response code is NOERROR.
+    pub async fn lookup(&self, name: &str, record_type: RecordType) -> Result<Vec<Record>> {
+        let query_name = crate::config::parse_fqdn(name).map_err(|e| format!("\"{name}\" {e}"))?;
+        let mut message = Message::new(rand::random::<u16>(), MessageType::Query, OpCode::Query);
+        message.add_query(Query::query(query_name, record_type));
+        message.metadata.recursion_desired = true;
+
+        let response = self.query(message).await?;
+        let rcode = response.metadata.response_code;
+        if rcode != ResponseCode::NoError {
+            return Err(format!("resolving \"{name}\" {}", dns::rcode_name(rcode)).into());
+        }
+        let answers = response.answers.into_iter();
+        Ok(answers
+            .filter(|answer| record_type == RecordType::ANY || answer.record_type() == record_type)
+            .collect())
+    }
+
+    /// The IPv4 and IPv6 addresses of `name`, fails when it has none.
+    pub async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let (ipv4, ipv6) = tokio::join!(self.lookup(name, RecordType::A), self.lookup(name, RecordType::AAAA));
+        let addrs = match (ipv4, ipv6) {
+            (Err(e), Err(_)) => return Err(e),
+            (ipv4, ipv6) => ipv4.into_iter().chain(ipv6).flatten().filter_map(|answer| match answer.data {
+                RData::A(addr) => Some(IpAddr::V4(addr.0)),
+                RData::AAAA(addr) => Some(IpAddr::V6(addr.0)),
+                _ => None,
+            }),
+        };
+        let addrs = addrs.collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(format!("\"{name}\" has no address").into());
+        }
+        Ok(addrs)
+    }
+
+    /// Make this the resolver of `dns2socks_resolve`, running its queries on `runtime`.
+    pub(crate) fn activate(&self, runtime: tokio::runtime::Handle) {
+        if let Ok(mut active) = ACTIVE.lock() {
+            *active = Some((self.clone(), runtime));
+        }
+    }
+
+    pub(crate) fn deactivate() {
+        if let Ok(mut active) = ACTIVE.lock() {
+            *active = None;
+        }
+    }
+
+    /// The resolver of the running instance, and the runtime to resolve on.
+    pub(crate) fn active() -> Option<(Resolver, tokio::runtime::Handle)> {
+        ACTIVE.lock().ok()?.clone()
+    }
+}
@@ -0,0 +1,250 @@
+//! A DNS2Socks instance embedded in another program, started from a builder and controlled through its handle.
+
+use crate::{Context, Instance, Listeners, Listening, Middleware, Rebind, Reloader, Resolver, SharedContext, Upstream, config::Config};
+use socks5_impl::Result;
+use std::{net::SocketAddr, sync::Arc, sync::atomic::Ordering, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+/// Where a started instance is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    Starting,
+    Running,
+    Stopped,
+}
+
+/// Counters of a running instance, see [`Dns2Socks::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub uptime: Duration,
+    /// Queries answered to clients.
+    pub queries: u64,
+    /// Queries being resolved.
+    pub in_flight: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_entries: u64,
+    /// Failures to get an answer from the upstream.
+    pub upstream_errors: u64,
+    /// Queries answered from the blocklists.
+    pub blocked: u64,
+    /// Forged or bogus responses dropped.
+    pub dropped_responses: u64,
+    /// Answers with private addresses blocked.
+    pub rebind_blocked: u64,
+    /// Queries rejected by access control.
+    pub acl_rejected: u64,
+    /// Queries dropped by rate limiting.
+    pub rate_limited: u64,
+}
+
+/// Builds a [`Dns2Socks`] instance, see [`Dns2Socks::builder`].
+#[derive(Clone, Default)]
+pub struct Dns2SocksBuilder {
+    config: Config,
+    shutdown_token: Option<CancellationToken>,
+    reloader: Option<Reloader>,
+    upstream: Option<Arc<dyn Upstream>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Dns2SocksBuilder {
+    pub fn config(&mut self, config: Config) -> &mut Self {
+        self.config = config;
+        self
+    }
+
+    /// Stop the instance when this token is cancelled, on top of [`Dns2Socks::shutdown`].
+    pub fn shutdown_token(&mut self, shutdown_token: CancellationToken) -> &mut Self {
+        self.shutdown_token = Some(shutdown_token);
+        self
+    }
+
+    /// Also apply the configurations given to this reloader, on top of [`Dns2Socks::reload`].
+    pub fn reloader(&mut self, reloader: Reloader) -> &mut Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// Send the queries that are not answered locally to this upstream instead of the remote DNS server through the
+    /// proxy. It is kept across reloads.
+    pub fn upstream(&mut self, upstream: Arc<dyn Upstream>) -> &mut Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Run every query through this middleware, after the middleware added before it and ahead of the built-in
+    /// stages, see [`Middleware`]. It is kept across reloads.
+    pub fn middleware(&mut self, middleware: Arc<dyn Middleware>) -> &mut Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Bind the listeners and start serving. Errors binding or in the configuration are returned here, so once this
+    /// returns the addresses are known and queries are accepted.
+    pub async fn start(&self) -> Result<Dns2Socks> {
+        let config = self.config.clone();
+        log::info!("Starting DNS2Socks listening on {}...", config.listen_addr);
+
+        let reloader = self.reloader.clone().unwrap_or_default();
+        let reload_requests = reloader.take_requests()?;
+        let shutdown_token = self
+            .shutdown_token
+            .as_ref()
+            .map_or_else(CancellationToken::new, |token| token.child_token());
+        let listeners = Listeners::bind(&config).await?;
+        let mut ctx = Context::new(config)?;
+        ctx.upstream = self.upstream.clone();
+        ctx.middleware = self.middleware.clone();
+        let ctx = Arc::new(ctx);
+
+        let mut instance = Instance::new(ctx, listeners.activated, &shutdown_token);
+        instance.spawn_background();
+        instance.spawn_listeners(listeners, Rebind::ALL);
+
+        let (state, state_receiver) = watch::channel(State::Starting);
+        let server = Dns2Socks {
+            ctx: instance.ctx.subscribe(),
+            listening: instance.listening.clone(),
+            started: instance.started,
+            state: state_receiver,
+            reloader,
+            shutdown_token,
+            task: Some(tokio::spawn(crate::serve(instance, reload_requests, state))),
+        };
+        Ok(server)
+    }
+}
+
+impl std::fmt::Debug for Dns2SocksBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dns2SocksBuilder")
+            .field("config", &self.config)
+            .field("shutdown_token", &self.shutdown_token)
+            .field("reloader", &self.reloader)
+            .field("upstream", &self.upstream.as_ref().map(|upstream| upstream.name()))
+            .field("middleware", &self.middleware.len())
+            .finish()
+    }
+}
+
+/// The handle of a running instance. Dropping it stops the instance, without waiting for it.
+///
+/// ```no_run
+/// # async fn example() -> socks5_impl::Result<()> {
+/// let mut config = dns2socks_core::Config::default();
+/// config.listen_addr("127.0.0.1:0".parse().unwrap());
+/// let server = dns2socks_core::Dns2Socks::builder().config(config).start().await?;
+/// server.ready().await?;
+/// println!("DNS on {:?}", server.local_udp_addr());
+/// server.shutdown().await
+/// # }
+/// ```
+pub struct Dns2Socks {
+    ctx: SharedContext,
+    listening: Listening,
+    started: std::time::Instant,
+    state: watch::Receiver<State>,
+    reloader: Reloader,
+    shutdown_token: CancellationToken,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl Dns2Socks {
+    pub fn builder() -> Dns2SocksBuilder {
+        Dns2SocksBuilder::default()
+    }
+
+    /// The address of the first UDP listener, the actual port when the configured one is 0.
+    pub fn local_udp_addr(&self) -> Option<SocketAddr> {
+        self.local_addr("udp")
+    }
+
+    /// The address of the first TCP listener, the actual port when the configured one is 0.
+    pub fn local_tcp_addr(&self) -> Option<SocketAddr> {
+        self.local_addr("tcp")
+    }
+
+    fn local_addr(&self, kind: &str) -> Option<SocketAddr> {
+        let listening = self.listening.lock().ok()?;
+        listening
+            .iter()
+            .find(|(listener, _)| *listener == kind)
+            .and_then(|(_, addr)| addr.parse().ok())
+    }
+
+    /// Wait until the listeners serve, fails if the instance stopped first.
+    pub async fn ready(&self) -> Result<()> {
+        let mut state = self.state.clone();
+        match state.wait_for(|state| *state != State::Starting).await.map(|state| *state) {
+            Ok(State::Running) => Ok(()),
+            _ => Err("DNS2Socks stopped before it was ready".into()),
+        }
+    }
+
+    pub async fn stats(&self) -> Stats {
+        let ctx = self.ctx.borrow().clone();
+        // The entry count is only up to date once the pending writes are applied.
+        ctx.cache.run_pending_tasks().await;
+        let (cache_hits, cache_misses) = ctx.metrics.cache_counts();
+        Stats {
+            uptime: self.started.elapsed(),
+            queries: ctx.metrics.queries_total(),
+            in_flight: ctx.metrics.in_flight_count(),
+            cache_hits,
+            cache_misses,
+            cache_entries: ctx.cache.entry_count(),
+            upstream_errors: ctx.metrics.upstream_errors_total(),
+            blocked: ctx.blocklist.blocked(),
+            dropped_responses: ctx.filtered_responses.load(Ordering::Relaxed),
+            rebind_blocked: ctx.rebind_blocked.load(Ordering::Relaxed),
+            acl_rejected: ctx.acl.rejected(),
+            rate_limited: ctx.rate_limiter.as_ref().map_or(0, |limiter| limiter.limited()),
+        }
+    }
+
+    /// A resolver answering from within the program like the listeners do, following the reloads.
+    pub fn resolver(&self) -> Resolver {
+        Resolver::with_context(self.ctx.clone())
+    }
+
+    /// Apply a new configuration, see [`Reloader`]. On error the instance keeps running with its current one.
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        self.reloader.reload(config).await
+    }
+
+    /// Stop accepting queries, let the ones in flight finish for up to the upstream timeout, and wait until the
+    /// instance is stopped.
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.shutdown_token.cancel();
+        self.join().await
+    }
+
+    /// Wait until the instance stops, on shutdown or on a listener error.
+    pub async fn wait(mut self) -> Result<()> {
+        self.join().await
+    }
+
+    async fn join(&mut self) -> Result<()> {
+        match self.task.take() {
+            Some(task) => task.await.map_err(|e| e.to_string())?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Dns2Socks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dns2Socks")
+            .field("listening", &self.listening)
+            .field("state", &*self.state.borrow())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Dns2Socks {
+    fn drop(&mut self) {
+        self.shutdown_token.cancel();
+    }
+}
@@ -0,0 +1,118 @@
+//! Name resolution with the Tor SOCKS extension commands RESOLVE (0xF0) and RESOLVE_PTR (0xF1), for proxies that
+//! do not support UDP ASSOCIATE. The DNS response is built locally from the single address or name they return.
+
+use crate::dns;
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{
+        RData, Record, RecordType,
+        rdata::{A, AAAA, PTR},
+    },
+};
+use socks5_impl::{
+    Result,
+    client::{Socks5Reader, Socks5Writer},
+    protocol::{Address, AuthMethod, Reply, UserKey},
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+const RESOLVE: u8 = 0xF0;
+const RESOLVE_PTR: u8 = 0xF1;
+
+/// The proxy does not tell how long an answer is valid for, so a fixed TTL is used.
+const RESOLVED_TTL: u32 = 60;
+
+/// The name of this upstream in the metrics, the query log and the admin API.
+pub(crate) const UPSTREAM_NAME: &str = "socks-resolve";
+
+/// Whether the query can be answered with RESOLVE or RESOLVE_PTR.
+pub(crate) fn is_supported(query: &Message) -> bool {
+    query.queries.len() == 1 && matches!(query.queries[0].query_type(), RecordType::A | RecordType::AAAA | RecordType::PTR)
+}
+
+/// Answer an A, AAAA or PTR query through the proxy.
+pub(crate) async fn resolve(proxy_addr: SocketAddr, auth: Option<UserKey>, query: &Message, timeout: Duration) -> Result<Message> {
+    let question = query.queries.first().ok_or("no question in query")?;
+    let name = question.name();
+    let (command, addr) = match question.query_type() {
+        RecordType::PTR => {
+            let net = name.parse_arpa_name().map_err(|e| e.to_string())?;
+            (RESOLVE_PTR, Address::from((net.addr(), 0)))
+        }
+        _ => (RESOLVE, Address::from((name.to_ascii().trim_end_matches('.'), 0))),
+    };
+
+    let reply = tokio::time::timeout(timeout, socks_command(proxy_addr, auth, command, &addr)).await??;
+    let resolved = match reply {
+        Ok(resolved) => resolved,
+        // Tor answers a name that does not exist with "host unreachable", everything else is a failure to resolve.
+        Err(Reply::HostUnreachable) => return Ok(dns::build_response(query, ResponseCode::NXDomain)),
+        Err(reply) => {
+            log::debug!("SOCKS RESOLVE of {} failed with \"{}\"", name, reply);
+            return Ok(dns::build_response(query, ResponseCode::ServFail));
+        }
+    };
+
+    // A name resolving to an address of the other family has no records of the queried type.
+    let rdata = match (question.query_type(), resolved) {
+        (RecordType::A, Address::SocketAddress(SocketAddr::V4(addr))) => Some(RData::A(A(*addr.ip()))),
+        (RecordType::AAAA, Address::SocketAddress(SocketAddr::V6(addr))) => Some(RData::AAAA(AAAA(*addr.ip()))),
+        (RecordType::PTR, Address::DomainAddress(domain, _)) => {
+            let domain = crate::config::parse_fqdn(&domain).map_err(|e| e.to_string())?;
+            Some(RData::PTR(PTR(domain)))
+        }
+        _ => None,
+    };
+    let mut response = dns::build_response(query, ResponseCode::NoError);
+    if let Some(rdata) = rdata {
+        response.add_answer(Record::from_rdata(name.clone(), RESOLVED_TTL, rdata));
+    }
+    Ok(response)
+}
+
+/// Send a single extension command and return the address of the reply, or the reply code if it failed.
+async fn socks_command(
+    proxy_addr: SocketAddr,
+    auth: Option<UserKey>,
+    command: u8,
+    addr: &Address,
+) -> Result<std::result::Result<Address, Reply>> {
+    let mut stream = BufStream::new(TcpStream::connect(proxy_addr).await?);
+
+    let mut methods = vec![AuthMethod::NoAuth];
+    if auth.is_some() {
+        methods.push(AuthMethod::UserPass);
+    }
+    stream.write_selection_msg(&methods).await?;
+    match (stream.read_selection_msg().await?, &auth) {
+        (AuthMethod::NoAuth, _) => {}
+        (AuthMethod::UserPass, Some(auth)) => {
+            stream.write_auth_version().await?;
+            stream.write_string(&auth.username).await?;
+            stream.write_string(&auth.password).await?;
+            stream.flush().await?;
+            stream.read_auth_version().await?;
+            stream.read_auth_status().await?;
+        }
+        (method, _) => return Err(socks5_impl::Error::InvalidAuthMethod(method)),
+    }
+
+    stream.write_version().await?;
+    stream.write_u8(command).await?;
+    stream.write_reserved().await?;
+    stream.write_address(addr).await?;
+    stream.flush().await?;
+
+    stream.read_version().await?;
+    let reply = Reply::try_from(stream.read_u8().await?)?;
+    stream.read_reserved().await?;
+    let resolved = stream.read_address().await?;
+    Ok(match reply {
+        Reply::Succeeded => Ok(resolved),
+        reply => Err(reply),
+    })
+}
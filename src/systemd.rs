@@ -0,0 +1,119 @@
+//! Support for running under systemd: socket activation (`LISTEN_FDS`) and readiness notification (`sd_notify`).
+//!
+//! Both are no-ops when the process is not started by systemd, or on platforms without Unix sockets.
+
+/// A listening socket inherited from the service manager.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) enum ActivatedSocket {
+    Udp(std::net::UdpSocket),
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Collect the sockets passed in through `LISTEN_FDS`, if they are meant for this process. Only the first call gets
+/// them, an instance started after that one binds its own sockets.
+#[cfg(unix)]
+pub(crate) fn listen_fds() -> std::io::Result<Vec<ActivatedSocket>> {
+    use std::io::{Error, ErrorKind::InvalidData};
+    use std::os::fd::{FromRawFd, RawFd};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    // The descriptors are owned by the sockets made from them, and closed with them. Unsetting the variables instead
+    // would race with the other threads reading the environment.
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+    let Some(pid) = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) else {
+        return Ok(Vec::new());
+    };
+    if pid != std::process::id() {
+        return Ok(Vec::new());
+    }
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+
+    let mut sockets = Vec::with_capacity(count.max(0) as usize);
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        // SAFETY: systemd hands these descriptors over to us, nothing else in the process owns them.
+        unsafe {
+            if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        let (sock_type, family) = socket_type_and_family(fd)?;
+        let socket = match (sock_type, family) {
+            (libc::SOCK_DGRAM, libc::AF_INET | libc::AF_INET6) => ActivatedSocket::Udp(unsafe { std::net::UdpSocket::from_raw_fd(fd) }),
+            (libc::SOCK_STREAM, libc::AF_INET | libc::AF_INET6) => ActivatedSocket::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+            (libc::SOCK_STREAM, libc::AF_UNIX) => ActivatedSocket::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) }),
+            _ => {
+                let e = format!("socket activation fd {fd} has unsupported type {sock_type} / family {family}");
+                return Err(Error::new(InvalidData, e));
+            }
+        };
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+#[cfg(unix)]
+fn socket_type_and_family(fd: std::os::fd::RawFd) -> std::io::Result<(libc::c_int, libc::c_int)> {
+    let mut sock_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the out pointers are valid for the sizes passed alongside them.
+    let ret = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut sock_type as *mut _ as *mut _, &mut len) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((sock_type, storage.ss_family as libc::c_int))
+}
+
+/// Send a state string such as `READY=1` or `STOPPING=1` to the service manager.
+#[cfg(unix)]
+pub(crate) fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notify(&path, state) {
+        log::warn!("sd_notify \"{}\" error \"{}\"", state, e);
+    }
+}
+
+#[cfg(unix)]
+fn send_notify(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    let bytes = path.as_bytes();
+    if let Some(name) = bytes.strip_prefix(b"@") {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let e = format!("abstract socket {:?} is not supported", name);
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, e));
+        }
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn notify(_state: &str) {}
@@ -0,0 +1,317 @@
+//! Where queries that are not answered locally are sent. The built-in upstreams go through the SOCKS5 proxy: UDP
+//! ASSOCIATE, CONNECT, or the Tor RESOLVE extension. A program embedding DNS2Socks can plug in its own with
+//! [`crate::Dns2SocksBuilder::upstream`].
+
+use crate::{config::Config, dns, dnstap::Dnstap, health::Health, log_dns_message, metrics, metrics::Metrics, socks_resolve};
+use hickory_proto::op::{Message, ResponseCode};
+use ipnet::IpNet;
+use socks5_impl::{
+    Error, Result, client,
+    protocol::{Address, UserKey},
+};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+/// Answers the queries that are not answered locally.
+///
+/// Caching, policies, DNS64, DNSSEC validation and rebinding protection apply around it, the upstream only exchanges
+/// a query for its response.
+#[async_trait::async_trait]
+pub trait Upstream: Send + Sync {
+    async fn exchange(&self, message: Message) -> Result<Message>;
+
+    /// The name of this upstream in the metrics, the query log and the admin API.
+    fn name(&self) -> String {
+        "custom".to_owned()
+    }
+}
+
+/// The proxy and remote server the built-in upstreams go through, and what they report to.
+#[derive(Clone)]
+struct Proxy {
+    addr: SocketAddr,
+    user_key: Option<UserKey>,
+    server: Address,
+    timeout: Duration,
+    bogus_nxdomain: Vec<IpNet>,
+    bogus_retry_tcp: bool,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    dnstap: Option<Arc<Dnstap>>,
+    filtered_responses: Arc<AtomicU64>,
+}
+
+impl Proxy {
+    fn new(config: &Config) -> Self {
+        Proxy {
+            addr: config.socks5_settings.addr,
+            user_key: config.socks5_settings.credentials.clone(),
+            server: config.dns_remote_server.clone(),
+            timeout: Duration::from_secs(config.timeout),
+            bogus_nxdomain: config.bogus_nxdomain.clone(),
+            bogus_retry_tcp: config.bogus_retry_tcp,
+            metrics: Arc::default(),
+            health: Arc::default(),
+            dnstap: None,
+            filtered_responses: Arc::default(),
+        }
+    }
+
+    /// The address of the remote DNS server, `None` when it is a name the proxy resolves.
+    fn server_addr(&self) -> Option<SocketAddr> {
+        match self.server {
+            Address::SocketAddress(addr) => Some(addr),
+            Address::DomainAddress(..) => None,
+        }
+    }
+}
+
+/// Forwards queries to the remote DNS server over UDP, through a SOCKS5 UDP ASSOCIATE.
+///
+/// Responses that can not be the answer to the query, such as forged ones, are dropped. With `--bogus-retry-tcp` the
+/// query is then retried over TCP.
+#[derive(Clone)]
+pub struct Socks5Udp(Proxy);
+
+/// Forwards queries to the remote DNS server over TCP, through a SOCKS5 CONNECT.
+#[derive(Clone)]
+pub struct Socks5Tcp(Proxy);
+
+/// Answers A, AAAA and PTR queries with the Tor SOCKS extension commands RESOLVE and RESOLVE_PTR.
+#[derive(Clone)]
+pub struct SocksResolve(Proxy);
+
+impl Socks5Udp {
+    pub fn new(config: &Config) -> Self {
+        Socks5Udp(Proxy::new(config))
+    }
+}
+
+impl Socks5Tcp {
+    pub fn new(config: &Config) -> Self {
+        Socks5Tcp(Proxy::new(config))
+    }
+}
+
+impl SocksResolve {
+    pub fn new(config: &Config) -> Self {
+        SocksResolve(Proxy::new(config))
+    }
+}
+
+#[async_trait::async_trait]
+impl Upstream for Socks5Udp {
+    async fn exchange(&self, message: Message) -> Result<Message> {
+        let domain = dns::extract_domain_from_dns_message(&message)?;
+        let response = match udp_via_socks5_server(&self.0, &message, &domain).await? {
+            Some(response) => response,
+            None => {
+                log::debug!("Retrying {:?} over TCP", domain);
+                forward_tcp(&self.0, &message, &domain).await?
+            }
+        };
+        log_dns_message("DNS query via UDP", &domain, &response);
+        Ok(response)
+    }
+
+    fn name(&self) -> String {
+        self.0.server.to_string()
+    }
+}
+
+#[async_trait::async_trait]
+impl Upstream for Socks5Tcp {
+    async fn exchange(&self, message: Message) -> Result<Message> {
+        let domain = dns::extract_domain_from_dns_message(&message)?;
+        let response = forward_tcp(&self.0, &message, &domain).await?;
+        log_dns_message("DNS query via TCP", &domain, &response);
+        Ok(response)
+    }
+
+    fn name(&self) -> String {
+        self.0.server.to_string()
+    }
+}
+
+#[async_trait::async_trait]
+impl Upstream for SocksResolve {
+    async fn exchange(&self, message: Message) -> Result<Message> {
+        let proxy = &self.0;
+        let domain = dns::extract_domain_from_dns_message(&message)?;
+        let response = socks_resolve::resolve(proxy.addr, proxy.user_key.clone(), &message, proxy.timeout)
+            .await
+            .map_err(|e| {
+                proxy.metrics.count_upstream_error(metrics::error_kind(&e));
+                format!("resolving \"{domain}\" {e}")
+            })?;
+        log_dns_message("DNS query via SOCKS RESOLVE", &domain, &response);
+        Ok(response)
+    }
+
+    fn name(&self) -> String {
+        socks_resolve::UPSTREAM_NAME.to_owned()
+    }
+}
+
+/// The upstreams of an instance without one of its own, reporting to its metrics, health and dnstap.
+#[derive(Clone)]
+pub(crate) struct Builtin {
+    pub(crate) udp: Socks5Udp,
+    pub(crate) tcp: Socks5Tcp,
+    pub(crate) socks_resolve: SocksResolve,
+}
+
+impl Builtin {
+    pub(crate) fn new(
+        config: &Config,
+        metrics: &Arc<Metrics>,
+        health: &Arc<Health>,
+        dnstap: Option<&Arc<Dnstap>>,
+        filtered_responses: &Arc<AtomicU64>,
+    ) -> Self {
+        let proxy = Proxy {
+            metrics: metrics.clone(),
+            health: health.clone(),
+            dnstap: dnstap.cloned(),
+            filtered_responses: filtered_responses.clone(),
+            ..Proxy::new(config)
+        };
+        Builtin {
+            udp: Socks5Udp(proxy.clone()),
+            tcp: Socks5Tcp(proxy.clone()),
+            socks_resolve: SocksResolve(proxy),
+        }
+    }
+}
+
+/// Forward a query to the remote DNS server over TCP.
+async fn forward_tcp(proxy: &Proxy, message: &Message, domain: &str) -> Result<Message> {
+    let mut buf = message.to_vec().map_err(|e| e.to_string())?;
+    let mut new_buf = (buf.len() as u16).to_be_bytes().to_vec();
+    new_buf.append(&mut buf);
+    let sent = SystemTime::now();
+    if let Some(dnstap) = &proxy.dnstap {
+        dnstap.forwarder_query(proxy.server_addr(), true, sent, &new_buf[2..]);
+    }
+    let data = tcp_via_socks5_server(proxy, &new_buf).await.map_err(|e| {
+        proxy.metrics.count_upstream_error(metrics::error_kind(&e));
+        format!("querying \"{domain}\" {e}")
+    })?;
+    if let Some(dnstap) = &proxy.dnstap {
+        dnstap.forwarder_response(proxy.server_addr(), true, sent, &new_buf[2..], &data[2..]);
+    }
+    let response = dns::parse_data_to_dns_message(&data, true).inspect_err(|_| proxy.metrics.count_upstream_error("bad_response"))?;
+    if let Some(problem) = dns::response_mismatch(message, &response) {
+        proxy.metrics.count_upstream_error("bad_response");
+        proxy.filtered_responses.fetch_add(1, Ordering::Relaxed);
+        return Err(format!("querying \"{domain}\" {problem}").into());
+    }
+    // There is no other response to wait for on a stream, so a bogus answer is taken for what it stands for.
+    if dns::contains_bogus_ip(&response, &proxy.bogus_nxdomain) {
+        proxy.filtered_responses.fetch_add(1, Ordering::Relaxed);
+        return Ok(dns::build_response(message, ResponseCode::NXDomain));
+    }
+    Ok(response)
+}
+
+/// Query over UDP, dropping responses that can not be the answer to `message` such as forged ones, and waiting for
+/// the genuine one. Returns `None` when a response was dropped and --bogus-retry-tcp asks to retry over TCP instead,
+/// and NXDOMAIN when nothing but bogus answers arrived before the timeout.
+async fn udp_via_socks5_server(proxy: &Proxy, message: &Message, domain: &str) -> Result<Option<Message>> {
+    let buf = message.to_vec().map_err(|e| e.to_string())?;
+    let start = tokio::time::Instant::now();
+    let client = client::ClientWrapper::datagram(proxy.addr, proxy.user_key.clone()).await;
+    proxy.health.proxy_handshake(&client);
+    let client = client.map_err(|e| {
+        proxy.metrics.count_upstream_error(metrics::error_kind(&e));
+        format!("preparing to query \"{domain}\" {e}")
+    })?;
+    proxy.metrics.observe_handshake("udp_associate", start.elapsed());
+    let sent = SystemTime::now();
+    if let Some(dnstap) = &proxy.dnstap {
+        dnstap.forwarder_query(proxy.server_addr(), false, sent, &buf);
+    }
+    client.send_to(&buf, &proxy.server).await.map_err(|e| {
+        proxy.metrics.count_upstream_error(metrics::error_kind(&e));
+        format!("querying \"{domain}\" {e}")
+    })?;
+
+    let deadline = tokio::time::Instant::now() + proxy.timeout;
+    let mut bogus_seen = false;
+    loop {
+        let mut data = Vec::new();
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        let received = client.recv(remaining, &mut data).await;
+        // Only bogus answers arrived, these stand for a name that does not exist.
+        if received.is_err() && bogus_seen {
+            return Ok(Some(dns::build_response(message, ResponseCode::NXDomain)));
+        }
+        received.map_err(|e| {
+            proxy.metrics.count_upstream_error(metrics::error_kind(&e));
+            format!("querying \"{domain}\" {e}")
+        })?;
+        if let Some(dnstap) = &proxy.dnstap {
+            dnstap.forwarder_response(proxy.server_addr(), false, sent, &buf, &data);
+        }
+        let problem = match dns::parse_data_to_dns_message(&data, false) {
+            Ok(response) => match dns::response_mismatch(message, &response) {
+                None if dns::contains_bogus_ip(&response, &proxy.bogus_nxdomain) => {
+                    bogus_seen = true;
+                    "bogus address"
+                }
+                None => return Ok(Some(response)),
+                Some(problem) => problem,
+            },
+            Err(_) => "malformed response",
+        };
+        proxy.filtered_responses.fetch_add(1, Ordering::Relaxed);
+        log::warn!("Dropped a response to {:?}, {}", domain, problem);
+        if proxy.bogus_retry_tcp {
+            return Ok(None);
+        }
+    }
+}
+
+/// Send a length prefixed query to the remote DNS server through a SOCKS5 CONNECT, and read the length prefixed response.
+async fn tcp_via_socks5_server(proxy: &Proxy, buf: &[u8]) -> Result<Vec<u8>> {
+    let timeout = proxy.timeout;
+    let start = tokio::time::Instant::now();
+    let handshake = async {
+        let s5_proxy = tokio::time::timeout(timeout, TcpStream::connect(proxy.addr)).await??;
+        let mut stream = BufStream::new(s5_proxy);
+        let target_server = proxy.server.clone();
+        let _addr = tokio::time::timeout(timeout, client::connect(&mut stream, target_server, proxy.user_key.clone())).await??;
+        Ok::<_, Error>(stream)
+    }
+    .await;
+    proxy.health.proxy_handshake(&handshake);
+    let mut stream = handshake?;
+    proxy.metrics.observe_handshake("connect", start.elapsed());
+
+    stream.write_all(buf).await?;
+    stream.flush().await?;
+
+    // Read the length prefix (2 bytes)
+    let mut len_buf = [0u8; 2];
+    tokio::time::timeout(timeout, stream.read_exact(&mut len_buf)).await??;
+    let len = u16::from_be_bytes(len_buf) as usize;
+
+    // Read the DNS message
+    let mut msg_buf = vec![0u8; len];
+    tokio::time::timeout(timeout, stream.read_exact(&mut msg_buf)).await??;
+
+    // Prepend the length prefix to match the expected format
+    let mut response_buf = len_buf.to_vec();
+    response_buf.extend(msg_buf);
+    Ok(response_buf)
+}